@@ -1,26 +1,147 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use ansi_term::Colour;
 use ansi_term::Colour::*;
+use lexer::quote;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ColorType {
-    Red, White
+    White, DarkRed, Purple, Green, LightGreen, Lime, Red, Grey,
+    Blue, DarkBlue, Magenta, LightRed, Orange
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-pub enum KeyType {
-    W, A, S, D
+// Every chat colour: its name in scripts, the control character that
+// switches chat to it and roughly how it looks.  Codes 0x09, 0x0A and 0x0D
+// would be read as whitespace or a line break, so their colours are left out.
+pub static COLORS : &'static [(&'static str, ColorType, char, (u8, u8, u8))] = &[
+    ("white", ColorType::White, '\u{01}', (255, 255, 255)),
+    ("darkred", ColorType::DarkRed, '\u{02}', (154, 0, 0)),
+    ("purple", ColorType::Purple, '\u{03}', (186, 129, 240)),
+    ("green", ColorType::Green, '\u{04}', (64, 255, 64)),
+    ("lightgreen", ColorType::LightGreen, '\u{05}', (191, 255, 144)),
+    ("lime", ColorType::Lime, '\u{06}', (162, 255, 71)),
+    ("red", ColorType::Red, '\u{07}', (255, 64, 64)),
+    ("grey", ColorType::Grey, '\u{08}', (197, 202, 208)),
+    ("blue", ColorType::Blue, '\u{0B}', (94, 152, 217)),
+    ("darkblue", ColorType::DarkBlue, '\u{0C}', (75, 105, 255)),
+    ("magenta", ColorType::Magenta, '\u{0E}', (211, 44, 230)),
+    ("lightred", ColorType::LightRed, '\u{0F}', (235, 75, 75)),
+    ("orange", ColorType::Orange, '\u{10}', (228, 174, 57))
+];
+
+impl ColorType {
+    pub fn named(name : &str) -> Option<ColorType> {
+        COLORS.iter().find(|c| c.0 == name).map(|c| c.1)
+    }
+
+    // The colour that looks most like the given one
+    pub fn nearest(r : u8, g : u8, b : u8) -> ColorType {
+        let distance = |&(cr, cg, cb) : &(u8, u8, u8)| {
+            let (dr, dg, db) = (cr as i32 - r as i32, cg as i32 - g as i32, cb as i32 - b as i32);
+            dr * dr + dg * dg + db * db
+        };
+        COLORS.iter().min_by_key(|c| distance(&c.3)).unwrap().1
+    }
+
+    // The colour a control character in chat switches to
+    pub fn coded(code : char) -> Option<ColorType> {
+        COLORS.iter().find(|c| c.2 == code).map(|c| c.1)
+    }
+
+    pub fn name(&self) -> &'static str {
+        COLORS.iter().find(|c| c.1 == *self).unwrap().0
+    }
+
+    pub fn code(&self) -> char {
+        COLORS.iter().find(|c| c.1 == *self).unwrap().2
+    }
+}
+
+// Every key that can be bound, by its name in scripts and in the console.
+// Digits are spelled out, since a bare digit is an integer.
+pub static KEYS : &'static [(&'static str, &'static str)] = &[
+    ("A", "a"), ("B", "b"), ("C", "c"), ("D", "d"), ("E", "e"), ("F", "f"), ("G", "g"), ("H", "h"), ("I", "i"), ("J", "j"), ("K", "k"), ("L", "l"), ("M", "m"),
+    ("N", "n"), ("O", "o"), ("P", "p"), ("Q", "q"), ("R", "r"), ("S", "s"), ("T", "t"), ("U", "u"), ("V", "v"), ("W", "w"), ("X", "x"), ("Y", "y"), ("Z", "z"),
+    ("KEY_0", "0"), ("KEY_1", "1"), ("KEY_2", "2"), ("KEY_3", "3"), ("KEY_4", "4"), ("KEY_5", "5"), ("KEY_6", "6"), ("KEY_7", "7"), ("KEY_8", "8"), ("KEY_9", "9"),
+    ("F1", "F1"), ("F2", "F2"), ("F3", "F3"), ("F4", "F4"), ("F5", "F5"), ("F6", "F6"), ("F7", "F7"), ("F8", "F8"), ("F9", "F9"), ("F10", "F10"), ("F11", "F11"), ("F12", "F12"),
+    ("MOUSE1", "MOUSE1"), ("MOUSE2", "MOUSE2"), ("MOUSE3", "MOUSE3"), ("MOUSE4", "MOUSE4"), ("MOUSE5", "MOUSE5"),
+    ("MWHEELUP", "MWHEELUP"), ("MWHEELDOWN", "MWHEELDOWN"),
+    ("KP_0", "KP_0"), ("KP_1", "KP_1"), ("KP_2", "KP_2"), ("KP_3", "KP_3"), ("KP_4", "KP_4"), ("KP_5", "KP_5"), ("KP_6", "KP_6"), ("KP_7", "KP_7"), ("KP_8", "KP_8"), ("KP_9", "KP_9"),
+    ("KP_DIVIDE", "KP_DIVIDE"), ("KP_MULTIPLY", "KP_MULTIPLY"), ("KP_MINUS", "KP_MINUS"), ("KP_PLUS", "KP_PLUS"),
+    ("KP_ENTER", "KP_ENTER"), ("KP_DEL", "KP_DEL"),
+    ("SPACE", "SPACE"), ("TAB", "TAB"), ("ENTER", "ENTER"), ("BACKSPACE", "BACKSPACE"), ("CAPSLOCK", "CAPSLOCK"),
+    ("UPARROW", "UPARROW"), ("DOWNARROW", "DOWNARROW"), ("LEFTARROW", "LEFTARROW"), ("RIGHTARROW", "RIGHTARROW"),
+    ("INS", "INS"), ("DEL", "DEL"), ("HOME", "HOME"), ("END", "END"), ("PGUP", "PGUP"), ("PGDN", "PGDN"),
+    ("ALT", "ALT"), ("CTRL", "CTRL"), ("SHIFT", "SHIFT")
+];
+
+// Keys that can be held while pressing another, as in ALT+Q
+static MODIFIERS : &'static [&'static str] = &["ALT", "CTRL", "SHIFT"];
+
+// A key from KEYS, by its index, optionally pressed with a modifier
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct KeyType {
+    pub key: u8,
+    pub modifier: Option<u8>
+}
+
+impl KeyType {
+    // Looks up a key by its name in scripts, such as F1 or ALT+Q
+    pub fn named(name : &str) -> Option<KeyType> {
+        let find = |name : &str| KEYS.iter().position(|&(n, _)| n == name).map(|i| i as u8);
+        match name.find('+') {
+            Some(i) => match (find(&name[..i]), find(&name[i + 1..])) {
+                (Some(modifier), Some(key)) => KeyType { key: modifier, modifier: None }.with(KeyType { key: key, modifier: None }),
+                _ => None
+            },
+            None => find(name).map(|key| KeyType { key: key, modifier: None })
+        }
+    }
+
+    pub fn is_modifier(&self) -> bool {
+        self.modifier.is_none() && MODIFIERS.contains(&KEYS[self.key as usize].0)
+    }
+
+    // The key pressed while this one is held, if this is a modifier.  The
+    // console can't bind one modifier held with another.
+    pub fn with(&self, key : KeyType) -> Option<KeyType> {
+        if self.is_modifier() && key.modifier.is_none() && !key.is_modifier() {
+            Some(KeyType { key: key.key, modifier: Some(self.key) })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(modifier) = self.modifier {
+            try!(write!(f, "{}+", KEYS[modifier as usize].0));
+        }
+        write!(f, "{}", KEYS[self.key as usize].0)
+    }
+}
+
+impl fmt::Debug for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
 }
 
 #[derive(Clone, Copy)]
 pub enum OpType {
-    Plus, Minus, Times, UPlus, UMinus
+    Plus, Minus, Times, UPlus, UMinus, Negate,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    And, Or, Not
 }
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Type {
     Bottom,
     Bool,
-    Int(u8, u8), // TODO: ensure low < high
+    Int(i32, i32), // Both bounds are inclusive, and low <= high once checked
     Color,
     Key,
     Printable,
@@ -29,10 +150,22 @@ pub enum Type {
 
 pub type Ident = String;
 
-pub enum Expr {
+// The values integers may take, so the widest type is int<INT_MIN, INT_MAX>
+pub static INT_MIN : i32 = -254;
+pub static INT_MAX : i32 = 254;
+
+// Byte offsets into the source a node was parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub lo: usize,
+    pub hi: usize
+}
+
+#[derive(Clone)]
+pub enum ExprKind {
     // Constants
     ConstBool(bool),
-    ConstInt(u8),
+    ConstInt(i32),
     ConstKey(KeyType),
     ConstColor(ColorType),
     ConstString(String),
@@ -42,12 +175,20 @@ pub enum Expr {
     Var(Ident),
     Binop(Box<Expr>, OpType, Box<Expr>),
     Unop(Ident, OpType),
+    Prefix(OpType, Box<Expr>),
 
     Call(Ident, Vec<Expr>),
     Elem(Box<Expr>, Box<Expr>)
 }
 
-pub enum Statement {
+#[derive(Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span
+}
+
+#[derive(Clone)]
+pub enum StatementKind {
     // Declarations and modifications
     Mutable(Type, Ident, Expr),
     Const(Type, Ident, Expr),
@@ -56,46 +197,89 @@ pub enum Statement {
     // Control flow
     Block(Vec<Statement>),
     Break,
+    If(Expr, Box<Statement>, Option<Box<Statement>>),
     Input(Vec<(KeyType, Statement)>),
+    Loop(Box<Statement>),
+    While(Expr, Box<Statement>),
     Return(Expr),
 
     // Side-effects
     Expr(Expr)
 }
 
+#[derive(Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span
+}
+
+// Values known at compile time
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i32),
+    Key(KeyType),
+    Color(ColorType),
+    Str(String),
+    List(Vec<String>)
+}
+
 pub struct Function {
     pub ret: Option<Type>,
     pub name: Ident,
     pub args: Vec<(Type, Ident)>,
-    pub body: Statement
+    pub body: Statement,
+    pub span: Span // The signature, not including the body
 }
 
 pub struct AST(pub Vec<Function>);
 
+// A comment, kept apart from the tree with the position it was written at,
+// so a printer can put it back between the statements around it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub span: Span,
+    pub text: String,         // Without the delimiters
+    pub block: bool,          // Written as /* */ rather than //
+    pub trails: Option<usize> // The end of the token before it, if that's on the same line
+}
+
 impl fmt::Display for AST {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for func in self.0.iter() {
             try!(write!(f, "{}\n\n", func))
         }
-        return Ok(());
+        write_comments(f, usize::max_value())
+    }
+}
+
+impl fmt::Display for Comment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.block {
+            write!(f, "{}", paint(Blue, format!("/*{}*/", self.text)))
+        } else {
+            write!(f, "{}", paint(Blue, format!("//{}", self.text)))
+        }
     }
 }
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write_comments(f, self.span.lo));
+
         match self.ret {
             Some(ref t) => try!(write!(f, "{} ", t)),
             None => ()
         }
 
-        try!(write!(f, "{}(", Purple.paint(self.name.to_string())));
+        try!(write!(f, "{}(", paint(Purple, self.name.to_string())));
 
         let mut first = true;
         for &(ref typ, ref name) in self.args.iter() {
             if !first {
                 try!(write!(f, ", "));
             }
-            try!(write!(f, "{} {}", typ, Cyan.paint(name.to_string())));
+            try!(write!(f, "{} {}", typ, paint(Cyan, name.to_string())));
             first = false;
         }
 
@@ -107,6 +291,87 @@ impl fmt::Display for Function {
 }
 
 
+// Whether output is colored, which is pointless when it isn't read in a
+// terminal
+static COLOR : AtomicBool = AtomicBool::new(true);
+
+pub fn set_color(enabled : bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+fn paint<S : Into<String>>(colour : Colour, text : S) -> String {
+    let text = text.into();
+    if COLOR.load(Ordering::Relaxed) {
+        colour.paint(text).to_string()
+    } else {
+        text
+    }
+}
+
+// Types to show next to each expression while printing a typed AST
+thread_local!(static ANNOTATIONS: RefCell<Option<HashMap<Span, Type>>> = RefCell::new(None));
+
+// Prints an AST with the type inferred for every expression that isn't a
+// constant
+pub struct Typed<'a>(pub &'a AST, pub &'a HashMap<Span, Type>);
+
+impl<'a> fmt::Display for Typed<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        ANNOTATIONS.with(|a| *a.borrow_mut() = Some(self.1.clone()));
+        let result = write!(f, "{}", self.0);
+        ANNOTATIONS.with(|a| *a.borrow_mut() = None);
+        result
+    }
+}
+
+// Comments that haven't been printed yet, in the order they were written
+thread_local!(static PENDING: RefCell<Vec<Comment>> = RefCell::new(vec![]));
+
+// Prints an AST with its comments put back before the statement that
+// followed them
+pub struct Commented<'a>(pub &'a AST, pub &'a [Comment]);
+
+impl<'a> fmt::Display for Commented<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        PENDING.with(|p| *p.borrow_mut() = self.1.to_vec());
+        let result = write!(f, "{}", self.0);
+        PENDING.with(|p| p.borrow_mut().clear());
+        result
+    }
+}
+
+// Writes every pending comment that ends before the offset, each on its own
+// line
+fn write_comments(f : &mut fmt::Formatter, before : usize) -> fmt::Result {
+    let comments : Vec<Comment> = PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+        let count = pending.iter().take_while(|c| c.span.hi <= before).count();
+        pending.drain(..count).collect()
+    });
+
+    for comment in comments {
+        write_indent(f);
+        try!(writeln!(f, "{}", comment));
+    }
+    Ok(())
+}
+
+// Ends the line of a statement that finished at the offset, keeping the
+// comments written after it on that line there
+fn end_line(f : &mut fmt::Formatter, hi : usize) -> fmt::Result {
+    let comments : Vec<Comment> = PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+        let (trailing, rest) : (Vec<Comment>, Vec<Comment>) = pending.drain(..).partition(|c| c.trails == Some(hi));
+        *pending = rest;
+        trailing
+    });
+
+    for comment in comments {
+        try!(write!(f, " {}", comment));
+    }
+    writeln!(f, "")
+}
+
 // Indentation for formatting
 // XXX: There must be a better way
 static mut indentation_level : i32 = 0;
@@ -132,59 +397,118 @@ fn undent() {
     }
 }
 
+// Blocks open on the current line, anything else goes indented on the next
+fn write_branch(f : &mut fmt::Formatter, s : &Statement) -> fmt::Result {
+    if let StatementKind::Block(_) = s.kind {
+        return write!(f, " {}", s);
+    }
+
+    try!(writeln!(f, ""));
+    indent();
+    let result = write!(f, "{}", s);
+    undent();
+    result
+}
+
 impl fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Statement::Mutable(ref typ, ref name, ref expr) => {
+        // Blocks open on the line of whatever they follow, so their comments
+        // go inside instead
+        if let StatementKind::Block(_) = self.kind {
+        } else {
+            try!(write_comments(f, self.span.lo));
+        }
+
+        match &self.kind {
+            &StatementKind::Mutable(ref typ, ref name, ref expr) => {
                 write_indent(f);
-                writeln!(f, "{} {} {} = {};", Red.paint("mut"), typ, Cyan.paint(name.to_string()), expr)
+                try!(write!(f, "{} {} {} = {};", paint(Red, "mut"), typ, paint(Cyan, name.to_string()), expr));
+                end_line(f, self.span.hi)
             },
-            &Statement::Const(ref typ, ref name, ref expr) => {
+            &StatementKind::Const(ref typ, ref name, ref expr) => {
                 write_indent(f);
-                writeln!(f, "{} {} = {};", typ, Cyan.paint(name.to_string()), expr)
+                try!(write!(f, "{} {} = {};", typ, paint(Cyan, name.to_string()), expr));
+                end_line(f, self.span.hi)
             },
-            &Statement::Assign(ref name, ref expr) => {
+            &StatementKind::Assign(ref name, ref expr) => {
                 write_indent(f);
-                writeln!(f, "{} = {};", Cyan.paint(name.to_string()), expr)
+                try!(write!(f, "{} = {};", paint(Cyan, name.to_string()), expr));
+                end_line(f, self.span.hi)
             },
-            &Statement::Block(ref stmts) => {
+            &StatementKind::Block(ref stmts) => {
                 try!(writeln!(f, "{{"));
                 indent();
 
                 for stmt in stmts {
+                    // Blocks expect to follow something on the same line
+                    if let StatementKind::Block(_) = stmt.kind {
+                        write_indent(f);
+                    }
                     try!(write!(f, "{}", stmt));
                 }
+                try!(write_comments(f, self.span.hi));
 
                 undent();
                 write_indent(f);
-                writeln!(f, "}}")
+                try!(write!(f, "}}"));
+                end_line(f, self.span.hi)
             },
-            &Statement::Input(ref branches) => {
+            &StatementKind::If(ref cond, ref then, ref otherwise) => {
                 write_indent(f);
-                try!(writeln!(f, "{} {{", Red.paint("input")));
+                try!(write!(f, "{} ({})", paint(Red, "if"), cond));
+                try!(write_branch(f, then));
+
+                if let &Some(ref otherwise) = otherwise {
+                    write_indent(f);
+                    try!(write!(f, "{}", paint(Red, "else")));
+                    try!(write_branch(f, otherwise));
+                }
+                Ok(())
+            },
+            &StatementKind::Input(ref branches) => {
+                write_indent(f);
+                try!(writeln!(f, "{} {{", paint(Red, "input")));
                 indent();
 
-                // TODO: handle printing of single statements
                 for &(ref key, ref arm) in branches {
+                    // Comments before an arm go above its key rather than
+                    // inside the arm
+                    try!(write_comments(f, arm.span.lo));
                     write_indent(f);
-                    try!(write!(f, "{:?} => {}", key, arm));
+                    try!(write!(f, "{:?} =>", key));
+                    try!(write_branch(f, arm));
                 }
+                try!(write_comments(f, self.span.hi));
 
                 undent();
                 write_indent(f);
-                writeln!(f, "}}")
+                try!(write!(f, "}}"));
+                end_line(f, self.span.hi)
             },
-            &Statement::Return(ref expr) => {
+            &StatementKind::Loop(ref body) => {
                 write_indent(f);
-                writeln!(f, "{} {};", Red.paint("return"), expr)
+                try!(write!(f, "{}", paint(Red, "loop")));
+                write_branch(f, body)
             },
-            &Statement::Break => {
+            &StatementKind::While(ref cond, ref body) => {
                 write_indent(f);
-                writeln!(f, "{};", Red.paint("break"))
+                try!(write!(f, "{} ({})", paint(Red, "while"), cond));
+                write_branch(f, body)
             },
-            &Statement::Expr(ref expr) => {
+            &StatementKind::Return(ref expr) => {
                 write_indent(f);
-                writeln!(f, "{};", expr)
+                try!(write!(f, "{} {};", paint(Red, "return"), expr));
+                end_line(f, self.span.hi)
+            },
+            &StatementKind::Break => {
+                write_indent(f);
+                try!(write!(f, "{};", paint(Red, "break")));
+                end_line(f, self.span.hi)
+            },
+            &StatementKind::Expr(ref expr) => {
+                write_indent(f);
+                try!(write!(f, "{};", expr));
+                end_line(f, self.span.hi)
             }
         }
     }
@@ -192,17 +516,41 @@ impl fmt::Display for Statement {
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Expr::ConstBool(ref b) => write!(f, "{}", Green.paint(b.to_string())),
-            &Expr::ConstInt(ref i) => write!(f, "{}", Green.paint(i.to_string())),
-            &Expr::ConstKey(ref k) => write!(f, "{}", Green.paint(format!("{:?}", k))),
-            &Expr::ConstColor(ref c) => write!(f, "{}", Green.paint(format!("{:?}", c))),
-            &Expr::ConstString(ref s) => write!(f, "\"{}\"", Green.paint(s.to_string())),
-            &Expr::Var(ref name) => write!(f, "{}", Cyan.paint(name.to_string())),
-            &Expr::Binop(ref l, ref o, ref r) => write!(f, "({} {} {})", l, o, r),
-            &Expr::Unop(ref l, ref o) => write!(f, "{}{}", l, o),
-            &Expr::Elem(ref list, ref elem) => write!(f, "{}[{}]", list, elem),
-            &Expr::ConstList(ref elems) => {
+        let annotation = match &self.kind {
+            &ExprKind::Var(_) | &ExprKind::Binop(_, _, _) | &ExprKind::Unop(_, _) | &ExprKind::Prefix(_, _) |
+            &ExprKind::Call(_, _) | &ExprKind::Elem(_, _) => ANNOTATIONS.with(|a| a.borrow().as_ref().and_then(|types| types.get(&self.span).cloned())),
+            _ => None
+        };
+
+        match annotation {
+            Some(t) if t != Type::Bottom => write!(f, "({} : {})", ExprText(self), t),
+            _ => write!(f, "{}", ExprText(self))
+        }
+    }
+}
+
+// An expression without its annotation
+struct ExprText<'a>(&'a Expr);
+
+impl<'a> fmt::Display for ExprText<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0.kind {
+            &ExprKind::ConstBool(ref b) => write!(f, "{}", paint(Green, b.to_string())),
+            &ExprKind::ConstInt(ref i) => write!(f, "{}", paint(Green, i.to_string())),
+            &ExprKind::ConstKey(ref k) => write!(f, "{}", paint(Green, format!("{:?}", k))),
+            &ExprKind::ConstColor(ref c) => write!(f, "{}", paint(Green, c.name())),
+            &ExprKind::ConstString(ref s) => write!(f, "{}", paint(Green, quote(s))),
+            &ExprKind::Var(ref name) => write!(f, "{}", paint(Cyan, name.to_string())),
+            &ExprKind::Binop(ref l, ref o, ref r) => write!(f, "({} {} {})", l, o, r),
+            &ExprKind::Unop(ref l, ref o) => write!(f, "{}{}", l, o),
+            &ExprKind::Prefix(ref o, ref e) => match (o, &e.kind) {
+                // Keep a negated negative from reading as --
+                (&OpType::Negate, &ExprKind::ConstInt(x)) if x < 0 => write!(f, "-({})", e),
+                (&OpType::Negate, &ExprKind::Prefix(OpType::Negate, _)) => write!(f, "-({})", e),
+                _ => write!(f, "{}{}", o, e)
+            },
+            &ExprKind::Elem(ref list, ref elem) => write!(f, "{}[{}]", list, elem),
+            &ExprKind::ConstList(ref elems) => {
                 try!(write!(f, "["));
 
                 let mut first = true;
@@ -211,14 +559,14 @@ impl fmt::Display for Expr {
                         try!(write!(f, ", "));
                     }
 
-                    try!(write!(f, "\"{}\"", Green.paint(elem.to_string())));
+                    try!(write!(f, "{}", paint(Green, quote(elem))));
                     first = false;
                 }
 
                 write!(f, "]")
             },
-            &Expr::Call(ref name, ref args) => {
-                try!(write!(f, "{}(", Purple.paint(name.to_string())));
+            &ExprKind::Call(ref name, ref args) => {
+                try!(write!(f, "{}(", paint(Purple, name.to_string())));
 
                 let mut first = true;
                 for arg in args {
@@ -238,7 +586,7 @@ impl fmt::Display for Expr {
 
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", Yellow.paint(match self {
+        write!(f, "{}", paint(Yellow, match self {
             &Type::Bottom => "".to_string(),
             &Type::Bool => "bool".to_string(),
             &Type::Color => "color".to_string(),
@@ -250,14 +598,85 @@ impl fmt::Display for Type {
     }
 }
 
+// Values are displayed as they would appear in chat
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Value::Bool(ref b) => write!(f, "{}", b),
+            &Value::Int(ref i) => write!(f, "{}", i),
+            &Value::Key(ref k) => write!(f, "{:?}", k),
+            &Value::Color(ref c) => write!(f, "{}", c.code()),
+            &Value::Str(ref s) => write!(f, "{}", s),
+            &Value::List(ref elems) => write!(f, "{}", elems.join(" "))
+        }
+    }
+}
+
+// Integer results outside of the range integers may take are errors
+fn checked(x : i32, o : OpType, y : i32, v : i32) -> Result<Value, String> {
+    if v > INT_MAX {
+        Err(format!("{} {} {} overflows", x, o, y))
+    } else if v < INT_MIN {
+        Err(format!("{} {} {} underflows", x, o, y))
+    } else {
+        Ok(Value::Int(v))
+    }
+}
+
+// Semantics of binary operators shared by every backend
+pub fn eval_binop(l : Value, o : OpType, r : Value) -> Result<Value, String> {
+    match (l, o, r) {
+        (Value::Int(x), OpType::Plus, Value::Int(y)) => checked(x, o, y, x + y),
+        (Value::Int(x), OpType::Minus, Value::Int(y)) => checked(x, o, y, x - y),
+        (Value::Int(x), OpType::Times, Value::Int(y)) => checked(x, o, y, x * y),
+        (Value::Str(s), OpType::Times, Value::Int(n)) if n >= 0 => Ok(Value::Str(s.repeat(n as usize))),
+        (l @ Value::Str(_), OpType::Plus, r) | (l @ Value::Color(_), OpType::Plus, r) => Ok(Value::Str(format!("{}{}", l, r))),
+        (Value::Int(x), OpType::Less, Value::Int(y)) => Ok(Value::Bool(x < y)),
+        (Value::Int(x), OpType::LessEqual, Value::Int(y)) => Ok(Value::Bool(x <= y)),
+        (Value::Int(x), OpType::Greater, Value::Int(y)) => Ok(Value::Bool(x > y)),
+        (Value::Int(x), OpType::GreaterEqual, Value::Int(y)) => Ok(Value::Bool(x >= y)),
+        (Value::Bool(x), OpType::And, Value::Bool(y)) => Ok(Value::Bool(x && y)),
+        (Value::Bool(x), OpType::Or, Value::Bool(y)) => Ok(Value::Bool(x || y)),
+        (l, OpType::Equal, r) => Ok(Value::Bool(l == r)),
+        (l, OpType::NotEqual, r) => Ok(Value::Bool(l != r)),
+        (_, o, _) => Err(format!("Operator {} cannot be evaluated", o))
+    }
+}
+
+// Semantics of prefix operators shared by every backend
+pub fn eval_prefix(o : OpType, v : Value) -> Result<Value, String> {
+    match (o, v) {
+        (OpType::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (OpType::Negate, Value::Int(x)) => Ok(Value::Int(-x)),
+        (o, _) => Err(format!("Operator {} cannot be evaluated", o))
+    }
+}
+
+// Integers stepped past either bound of their type wrap around to the other,
+// like the ring of aliases that holds them in a config
+pub fn wrap(v : i32, low : i32, high : i32) -> i32 {
+    let size = high - low + 1;
+    low + ((v - low) % size + size) % size
+}
+
 impl fmt::Display for OpType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", match self {
             &OpType::Plus => "+",
-            &OpType::Minus => "+",
-            &OpType::Times => "+",
+            &OpType::Minus => "-",
+            &OpType::Times => "*",
             &OpType::UPlus => "++",
-            &OpType::UMinus => "--"
+            &OpType::UMinus => "--",
+            &OpType::Negate => "-",
+            &OpType::Equal => "==",
+            &OpType::NotEqual => "!=",
+            &OpType::Less => "<",
+            &OpType::LessEqual => "<=",
+            &OpType::Greater => ">",
+            &OpType::GreaterEqual => ">=",
+            &OpType::And => "&&",
+            &OpType::Or => "||",
+            &OpType::Not => "!"
         })
     }
 }
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use ast::*;
+use ir::{Action, Program};
+
+pub struct Alias {
+    pub name: String,
+    pub commands: Vec<String>
+}
+
+// Output size of a single integer variable
+pub struct StateReport {
+    pub name: Ident,
+    pub low: i32,
+    pub high: i32,
+    pub aliases: usize,
+    pub bytes: usize
+}
+
+// How much an optimization pass shrank the config
+pub struct PassReport {
+    pub name: &'static str,
+    pub before: usize,
+    pub after: usize
+}
+
+// A complete config: alias definitions followed by the commands run on exec
+pub struct Config {
+    pub aliases: Vec<Alias>,
+    pub init: Vec<String>,
+    pub report: Vec<StateReport>,
+    pub passes: Vec<PassReport>
+}
+
+// The console's name for a key, which joins a modifier on with a +
+pub fn key_name(key : &KeyType) -> String {
+    match key.modifier {
+        Some(modifier) => format!("{}+{}", KEYS[modifier as usize].1, KEYS[key.key as usize].1),
+        None => KEYS[key.key as usize].1.to_string()
+    }
+}
+
+// What the game binds keys to out of the box, which is what a key goes back
+// to once no input is waiting for it.  Other keys are left unbound.
+static DEFAULT_BINDS : &'static [(&'static str, &'static str)] = &[
+    ("Q", "dota_ability_execute 0"), ("W", "dota_ability_execute 1"), ("E", "dota_ability_execute 2"),
+    ("D", "dota_ability_execute 3"), ("F", "dota_ability_execute 4"), ("R", "dota_ability_execute 5"),
+    ("Z", "dota_item_execute 0"), ("X", "dota_item_execute 1"), ("C", "dota_item_execute 2"),
+    ("V", "dota_item_execute 3"), ("B", "dota_item_execute 4"), ("N", "dota_item_execute 5"),
+    ("A", "mc_attack"), ("M", "mc_move"), ("S", "dota_stop")
+];
+
+pub fn default_bind(key : &KeyType) -> Option<&'static str> {
+    if key.modifier.is_some() {
+        return None;
+    }
+    DEFAULT_BINDS.iter().find(|&&(name, _)| name == KEYS[key.key as usize].0).map(|&(_, command)| command)
+}
+
+// Whether a command is one the game runs when a key is back to its default
+pub fn game_command(name : &str) -> bool {
+    DEFAULT_BINDS.iter().any(|&(_, command)| command.split(' ').next() == Some(name))
+}
+
+fn command(action : &Action) -> String {
+    match action {
+        &Action::Say(ref text) => format!("say {}", text),
+        &Action::SayTeam(ref text) => format!("say_team {}", text),
+        &Action::Bind(ref key, ref state) => format!("bind {} {}", key_name(key), state),
+        &Action::SetAlias(ref slot, ref state) => format!("alias {} {}", slot, state),
+        &Action::Run(ref state) => state.clone(),
+        &Action::Game(ref command) => command.clone()
+    }
+}
+
+// Writes each state out as an alias
+pub fn emit(p : &Program) -> Config {
+    let aliases : Vec<Alias> = p.states.iter().map(|state| Alias {
+        name: state.name.clone(),
+        commands: state.actions.iter().map(command).collect()
+    }).collect();
+
+    let report = {
+        let by_name : HashMap<&str, &Alias> = aliases.iter().map(|a| (&*a.name, a)).collect();
+        p.variables.iter().map(|variable| {
+            let owned : Vec<&Alias> = variable.states.iter().filter_map(|name| by_name.get(&**name).cloned()).collect();
+            StateReport {
+                name: variable.name.clone(),
+                low: variable.low,
+                high: variable.high,
+                aliases: owned.len(),
+                bytes: owned.iter().map(|a| a.to_string().len() + 1).sum()
+            }
+        }).collect()
+    };
+
+    Config { aliases: aliases, init: p.entry.iter().map(command).collect(), report: report, passes: vec![] }
+}
+
+impl Config {
+    // Bytes taken by everything but the comments
+    pub fn size(&self) -> usize {
+        self.aliases.iter().map(|a| a.to_string().len() + 1).sum::<usize>() + self.init.iter().map(|c| c.len() + 1).sum::<usize>()
+    }
+}
+
+impl fmt::Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "alias {} \"{}\"", self.name, self.commands.join("; "))
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "// Generated by clockwerk"));
+
+        for state in self.report.iter() {
+            try!(writeln!(f, "// {} int<{}, {}>: {} aliases, {} bytes", state.name, state.low, state.high, state.aliases, state.bytes));
+        }
+
+        for pass in self.passes.iter() {
+            try!(writeln!(f, "// {}: {} -> {} bytes", pass.name, pass.before, pass.after));
+        }
+
+        for alias in self.aliases.iter() {
+            try!(writeln!(f, "{}", alias));
+        }
+
+        for command in self.init.iter() {
+            try!(writeln!(f, "{}", command));
+        }
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,192 @@
+use std::cmp;
+use std::fmt;
+
+use ast::Span;
+
+// Every kind of problem we report, so tools can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    SyntaxError,
+    UndefinedVariable,
+    UndefinedFunction,
+    DuplicateDefinition,
+    DuplicateBranch,
+    ArityMismatch,
+    TypeMismatch,
+    InvalidOperator,
+    ImmutableAssignment,
+    IndexOutOfBounds,
+    IntegerOutOfRange,
+    MissingReturn,
+    UnexpectedReturn,
+    BreakOutsideLoop,
+    UnreachableCode,
+    RecursiveCall,
+    CannotInline,
+    IntegerOverflow,
+    EmptyRange,
+    UnsafeText,
+    NoDefaultBind,
+    UnorderedModification,
+    MutableType,
+    CopiedFunction
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+// Extra context attached to a diagnostic, optionally pointing at source
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub span: Option<Span>,
+    pub message: String
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: Code,
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+    pub notes: Vec<Note>
+}
+
+impl Code {
+    // Stable identifiers, so don't reorder these
+    pub fn id(&self) -> &'static str {
+        match self {
+            &Code::SyntaxError => "E0001",
+            &Code::UndefinedVariable => "E0002",
+            &Code::UndefinedFunction => "E0003",
+            &Code::DuplicateDefinition => "E0004",
+            &Code::DuplicateBranch => "E0005",
+            &Code::ArityMismatch => "E0006",
+            &Code::TypeMismatch => "E0007",
+            &Code::InvalidOperator => "E0008",
+            &Code::ImmutableAssignment => "E0009",
+            &Code::IndexOutOfBounds => "E0010",
+            &Code::IntegerOutOfRange => "E0011",
+            &Code::MissingReturn => "E0012",
+            &Code::UnexpectedReturn => "E0013",
+            &Code::BreakOutsideLoop => "E0014",
+            &Code::UnreachableCode => "E0015",
+            &Code::RecursiveCall => "E0016",
+            &Code::CannotInline => "E0017",
+            &Code::IntegerOverflow => "E0018",
+            &Code::EmptyRange => "E0019",
+            &Code::UnsafeText => "E0020",
+            &Code::NoDefaultBind => "E0021",
+            &Code::UnorderedModification => "E0022",
+            &Code::MutableType => "E0023",
+            &Code::CopiedFunction => "E0024"
+        }
+    }
+}
+
+// 1-based line and column of a byte offset
+fn line_col(source : &str, offset : usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map_or(0, |x| x + 1)..].chars().count() + 1;
+    (line, column)
+}
+
+// The line of source containing the span, with the span underlined by carets
+fn snippet(source : &str, span : Span) -> String {
+    let lo = span.lo;
+    let start = source[..lo].rfind('\n').map_or(0, |x| x + 1);
+    let end = source[lo..].find('\n').map_or(source.len(), |x| lo + x);
+
+    // Keep tabs so the carets line up however the line is displayed
+    let padding : String = source[start..lo].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let hi = cmp::min(cmp::max(span.hi, lo), end);
+    let width = cmp::max(source[lo..hi].trim_end().chars().count(), 1);
+
+    format!("{}\n{}{}\n", &source[start..end], padding, "^".repeat(width))
+}
+
+impl Diagnostic {
+    pub fn error(code : Code, span : Span, message : String) -> Diagnostic {
+        Diagnostic { code: code, severity: Severity::Error, span: span, message: message, notes: vec![] }
+    }
+
+    pub fn warning(code : Code, span : Span, message : String) -> Diagnostic {
+        Diagnostic { code: code, severity: Severity::Warning, span: span, message: message, notes: vec![] }
+    }
+
+    pub fn with_note(mut self, span : Option<Span>, message : String) -> Diagnostic {
+        self.notes.push(Note { span: span, message: message });
+        self
+    }
+
+    // Formats the diagnostic along with the offending line of source
+    pub fn render(&self, filename : &str, source : &str) -> String {
+        let (line, column) = line_col(source, self.span.lo);
+        let mut out = format!("{}:{}:{}: {}[{}]: {}\n{}",
+                              filename, line, column, self.severity, self.code.id(), self.message, snippet(source, self.span));
+
+        for note in self.notes.iter() {
+            match note.span {
+                Some(span) => {
+                    let (line, column) = line_col(source, span.lo);
+                    out.push_str(&format!("{}:{}:{}: note: {}\n{}", filename, line, column, note.message, snippet(source, span)));
+                },
+                None => out.push_str(&format!("  = note: {}\n", note.message))
+            }
+        }
+
+        out
+    }
+}
+
+pub fn has_errors(diagnostics : &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            &Severity::Error => "error",
+            &Severity::Warning => "warning"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(lo : usize, hi : usize) -> Span {
+        Span { lo: lo, hi: hi }
+    }
+
+    #[test]
+    fn render_caret() {
+        let source = "main() {\n    int x = y;\n}\n";
+        let d = Diagnostic::error(Code::UndefinedVariable, span(21, 22), "Undefined variable y".to_string());
+        assert_eq!(d.render("a.cw", source),
+                   "a.cw:2:13: error[E0002]: Undefined variable y\n    int x = y;\n            ^\n");
+    }
+
+    #[test]
+    fn render_tabs() {
+        let source = "main() {\n\tint x = y;\n}\n";
+        let d = Diagnostic::warning(Code::UnreachableCode, span(14, 19), "Unreachable".to_string());
+        assert_eq!(d.render("a.cw", source),
+                   "a.cw:2:6: warning[E0015]: Unreachable\n\tint x = y;\n\t    ^^^^^\n");
+    }
+
+    #[test]
+    fn render_multiline_span() {
+        // Only the first line is shown, underlined to its end
+        let source = "main() {\n    loop {  \n    }\n}\n";
+        let d = Diagnostic::error(Code::SyntaxError, span(13, 25), "Bad loop".to_string())
+            .with_note(None, "loops run forever".to_string())
+            .with_note(Some(span(0, 4)), "in main".to_string());
+        assert_eq!(d.render("a.cw", source),
+                   "a.cw:2:5: error[E0001]: Bad loop\n    loop {  \n    ^^^^^^\n  = note: loops run forever\na.cw:1:1: note: in main\nmain() {\n^^^^\n");
+    }
+}
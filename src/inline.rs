@@ -0,0 +1,564 @@
+use std::collections::{HashMap, HashSet};
+
+use ast::*;
+use diagnostic::{Code, Diagnostic};
+use scope::Scopes;
+
+// The console has no call stack, so calls to user functions are replaced by a
+// copy of their body.  Locals of an inlined body are renamed with a dot,
+// which user identifiers can't contain, so they never clash with the
+// caller's names.
+//
+// Functions that wait for input are the exception when their arguments and
+// result are integers or booleans: they're kept whole and the code generator
+// calls them through a continuation, so they aren't duplicated at every call
+// site.  Other values can't be kept in the console while a call waits, so
+// functions taking or returning them are still copied, with a warning.
+
+type FunctionTable<'a> = HashMap<Ident, &'a Function>;
+
+// What each name in scope refers to after inlining
+type Substitution = Scopes<Expr>;
+
+struct Inliner<'a> {
+    functions: FunctionTable<'a>,
+    shared: HashSet<Ident>,
+    current: Ident, // The function whose body is being rewritten
+    stack: Vec<(Ident, Span)>, // Calls currently being inlined, innermost last
+    counter: u32
+}
+
+// Where a return inside an inlined body leaves control
+#[derive(Clone, Copy, PartialEq)]
+enum Position {
+    Tail,   // Nothing follows, so storing the result is enough
+    Loop,   // Breaking out of the enclosing loop ends the function
+    Nested  // Neither, so the return can't be translated
+}
+
+// Collects the functions called by an expression
+fn expr_calls<'b>(e : &'b Expr, calls : &mut Vec<&'b Ident>) {
+    match &e.kind {
+        &ExprKind::Call(ref func, ref args) => {
+            calls.push(func);
+            for arg in args.iter() {
+                expr_calls(arg, calls);
+            }
+        },
+        &ExprKind::Binop(ref l, _, ref r) | &ExprKind::Elem(ref l, ref r) => {
+            expr_calls(l, calls);
+            expr_calls(r, calls);
+        },
+        &ExprKind::Prefix(_, ref operand) => expr_calls(operand, calls),
+        _ => ()
+    }
+}
+
+// Collects the functions called by a statement, returning whether it waits
+// for input itself
+fn statement_calls<'b>(s : &'b Statement, calls : &mut Vec<&'b Ident>) -> bool {
+    match &s.kind {
+        &StatementKind::Mutable(_, _, ref e) | &StatementKind::Const(_, _, ref e) | &StatementKind::Assign(_, ref e) |
+        &StatementKind::Return(ref e) | &StatementKind::Expr(ref e) => {
+            expr_calls(e, calls);
+            false
+        },
+        &StatementKind::Block(ref stmts) => stmts.iter().fold(false, |waits, stmt| statement_calls(stmt, calls) || waits),
+        &StatementKind::If(ref cond, ref then, ref otherwise) => {
+            expr_calls(cond, calls);
+            let waits = statement_calls(then, calls);
+            otherwise.as_ref().map_or(false, |x| statement_calls(x, calls)) || waits
+        },
+        &StatementKind::Input(ref branches) => {
+            for &(_, ref arm) in branches.iter() {
+                statement_calls(arm, calls);
+            }
+            true
+        },
+        &StatementKind::Loop(ref body) => statement_calls(body, calls),
+        &StatementKind::While(ref cond, ref body) => {
+            expr_calls(cond, calls);
+            statement_calls(body, calls)
+        },
+        &StatementKind::Break => false
+    }
+}
+
+// Whether calling the function can wait for input
+fn waits(func : &Ident, functions : &FunctionTable, seen : &mut HashSet<Ident>) -> bool {
+    let f = match functions.get(&*func) {
+        Some(f) => f,
+        None => return false
+    };
+
+    if !seen.insert(func.clone()) {
+        return false;
+    }
+
+    let mut calls = vec![];
+    statement_calls(&f.body, &mut calls) || calls.into_iter().any(|g| waits(g, functions, seen))
+}
+
+// Whether values of the type can be passed in state variables
+fn storable(t : &Type) -> bool {
+    match t {
+        &Type::Int(_, _) | &Type::Bool => true,
+        _ => false
+    }
+}
+
+// Whether the function can be called through a continuation.  Arguments and
+// results are passed in state variables.
+fn shareable(f : &Function, functions : &FunctionTable) -> bool {
+    f.name != "main" && f.args.iter().all(|&(ref t, _)| storable(t)) && f.ret.as_ref().map_or(true, storable) &&
+        waits(&f.name, functions, &mut HashSet::new())
+}
+
+// Warns about functions that wait for input but are copied into more than
+// one call site anyway, since the values they take can't be stored
+pub fn copied(t : &AST) -> Vec<Diagnostic> {
+    let functions : FunctionTable = t.0.iter().map(|f| (f.name.clone(), f)).collect();
+    let mut calls = vec![];
+    for f in t.0.iter() {
+        statement_calls(&f.body, &mut calls);
+    }
+
+    t.0.iter().filter(|f| f.name != "main" && !shareable(f, &functions) && waits(&f.name, &functions, &mut HashSet::new())).filter_map(|f| {
+        let count = calls.iter().filter(|&&name| *name == f.name).count();
+        if count < 2 {
+            return None;
+        }
+
+        let kept = f.args.iter().map(|x| x.0).chain(f.ret).find(|t| !storable(t)).unwrap();
+        Some(Diagnostic::warning(Code::CopiedFunction, f.span, format!("Function {} waits for input, but is copied into each of its {} calls", f.name, count))
+             .with_note(None, format!("only functions passing integers and booleans are shared, and {} passes a {}", f.name, kept)))
+    }).collect()
+}
+
+fn statement(kind : StatementKind, span : Span) -> Statement {
+    Statement { kind: kind, span: span }
+}
+
+fn block(mut stmts : Vec<Statement>, span : Span) -> Statement {
+    if stmts.len() == 1 {
+        return stmts.pop().unwrap();
+    }
+    statement(StatementKind::Block(stmts), span)
+}
+
+// Arguments that are cheap and side-effect free are substituted directly
+fn substitutable(e : &Expr) -> bool {
+    match &e.kind {
+        &ExprKind::ConstBool(_) | &ExprKind::ConstInt(_) | &ExprKind::ConstKey(_) | &ExprKind::ConstColor(_) => true,
+        &ExprKind::ConstString(_) | &ExprKind::ConstList(_) | &ExprKind::Var(_) => true,
+        _ => false
+    }
+}
+
+// A placeholder for results that are assigned before they're read
+fn default_value(t : &Type, span : Span) -> Expr {
+    let kind = match t {
+        &Type::Int(low, _) => ExprKind::ConstInt(low),
+        &Type::Key => ExprKind::ConstKey(KeyType { key: 0, modifier: None }),
+        &Type::Color => ExprKind::ConstColor(ColorType::White),
+        &Type::Printable => ExprKind::ConstString(String::new()),
+        &Type::PrintableList(n) => ExprKind::ConstList(vec![String::new(); n as usize]),
+        &Type::Bool | &Type::Bottom => ExprKind::ConstBool(false)
+    };
+    Expr { kind: kind, span: span }
+}
+
+// Whether every return in s can be translated at the given position
+fn translatable(s : &Statement, position : Position) -> bool {
+    match &s.kind {
+        &StatementKind::Return(_) => position != Position::Nested,
+        &StatementKind::Block(ref stmts) => stmts.iter().enumerate().all(|(i, stmt)| {
+            let last = i + 1 == stmts.len();
+            translatable(stmt, if position == Position::Tail && !last { Position::Nested } else { position })
+        }),
+        &StatementKind::If(_, ref then, ref otherwise) =>
+            translatable(then, position) && otherwise.as_ref().map_or(true, |x| translatable(x, position)),
+        &StatementKind::Input(ref branches) => branches.iter().all(|&(_, ref arm)| translatable(arm, position)),
+        &StatementKind::Loop(ref body) | &StatementKind::While(_, ref body) =>
+            translatable(body, if position == Position::Tail { Position::Loop } else { Position::Nested }),
+        _ => true
+    }
+}
+
+fn returns(s : &Statement) -> bool {
+    !translatable(s, Position::Nested)
+}
+
+// Replaces each return with an assignment to result, breaking out of the
+// enclosing loop where more statements would follow
+fn translate(s : Statement, result : &Ident, position : Position) -> Statement {
+    let span = s.span;
+    let kind = match s.kind {
+        StatementKind::Return(e) => {
+            let assign = statement(StatementKind::Assign(result.clone(), e), span);
+            if position == Position::Loop {
+                StatementKind::Block(vec![assign, statement(StatementKind::Break, span)])
+            } else {
+                assign.kind
+            }
+        },
+        StatementKind::Block(stmts) => {
+            let length = stmts.len();
+            StatementKind::Block(stmts.into_iter().enumerate().map(|(i, stmt)| {
+                let last = i + 1 == length;
+                translate(stmt, result, if position == Position::Tail && !last { Position::Nested } else { position })
+            }).collect())
+        },
+        StatementKind::If(cond, then, otherwise) =>
+            StatementKind::If(cond, Box::new(translate(*then, result, position)),
+                              otherwise.map(|x| Box::new(translate(*x, result, position)))),
+        StatementKind::Input(branches) =>
+            StatementKind::Input(branches.into_iter().map(|(key, arm)| (key, translate(arm, result, position))).collect()),
+        StatementKind::Loop(body) => {
+            let inner = if position == Position::Tail { Position::Loop } else { Position::Nested };
+            StatementKind::Loop(Box::new(translate(*body, result, inner)))
+        },
+        StatementKind::While(cond, body) => {
+            let inner = if position == Position::Tail { Position::Loop } else { Position::Nested };
+            StatementKind::While(cond, Box::new(translate(*body, result, inner)))
+        },
+        kind => kind
+    };
+    statement(kind, span)
+}
+
+impl<'a> Inliner<'a> {
+    // The name a declaration gets: unchanged in the function being inlined
+    // into, fresh in every inlined copy
+    fn local(&self, var : &Ident, copy : Option<(&Ident, u32)>) -> Ident {
+        match copy {
+            Some((func, n)) => format!("{}.{}.{}", func, var, n),
+            None => var.clone()
+        }
+    }
+
+    fn target(&self, var : &Ident, names : &Substitution) -> Ident {
+        match names.get(&*var) {
+            Some(&Expr { kind: ExprKind::Var(ref name), .. }) => name.clone(),
+            _ => var.clone()
+        }
+    }
+
+    // Rewrites an expression, moving the bodies of any calls it makes into
+    // statements that run before it
+    fn expr(&mut self, e : &Expr, names : &Substitution, pre : &mut Vec<Statement>) -> Result<Expr, Diagnostic> {
+        let kind = match &e.kind {
+            &ExprKind::Var(ref name) => match names.get(&*name) {
+                Some(replacement) => replacement.kind.clone(),
+                None => ExprKind::Var(name.clone())
+            },
+            &ExprKind::Binop(ref l, o, ref r) => {
+                let l = try!(self.expr(l, names, pre));
+
+                // Hoisting a call out of the right operand would run it even
+                // when the operator short-circuits
+                let mut inner = vec![];
+                let r = try!(self.expr(r, names, &mut inner));
+                match o {
+                    OpType::And | OpType::Or if !inner.is_empty() =>
+                        return Err(Diagnostic::error(Code::CannotInline, r.span,
+                                                     format!("Functions called on the right of {} cannot be inlined", o))),
+                    _ => pre.extend(inner)
+                }
+
+                ExprKind::Binop(Box::new(l), o, Box::new(r))
+            },
+            &ExprKind::Unop(ref name, o) => ExprKind::Unop(self.target(name, names), o),
+            &ExprKind::Prefix(o, ref operand) => ExprKind::Prefix(o, Box::new(try!(self.expr(operand, names, pre)))),
+            &ExprKind::Elem(ref list, ref index) => {
+                let list = try!(self.expr(list, names, pre));
+                let index = try!(self.expr(index, names, pre));
+                ExprKind::Elem(Box::new(list), Box::new(index))
+            },
+            &ExprKind::Call(ref func, ref args) => {
+                let mut values = vec![];
+                for arg in args.iter() {
+                    values.push(try!(self.expr(arg, names, pre)));
+                }
+
+                // Builtins are left for the code generator
+                if !self.functions.contains_key(&*func) {
+                    ExprKind::Call(func.clone(), values)
+                } else {
+                    match try!(self.call(func, values, e.span, pre)) {
+                        Some(result) => result.kind,
+                        None => return Err(Diagnostic::error(Code::CannotInline, e.span, format!("Function {} has no value to use", func)))
+                    }
+                }
+            },
+            kind => kind.clone()
+        };
+
+        Ok(Expr { kind: kind, span: e.span })
+    }
+
+    fn check_recursion(&self, func : &Ident, span : Span) -> Result<(), Diagnostic> {
+        if *func == self.current || self.stack.iter().any(|&(ref name, _)| name == func) {
+            let mut d = Diagnostic::error(Code::RecursiveCall, span, format!("Function {} is called recursively, but the console has no call stack", func));
+            for &(ref name, call) in self.stack.iter().rev() {
+                d = d.with_note(Some(call), format!("{} is called here", name));
+                if name == func {
+                    break;
+                }
+            }
+            return Err(d);
+        }
+        Ok(())
+    }
+
+    // Inlines a call, returning the expression holding its result.  Calls to
+    // shared functions stay calls, with their result given a name.
+    fn call(&mut self, func : &Ident, args : Vec<Expr>, span : Span, pre : &mut Vec<Statement>) -> Result<Option<Expr>, Diagnostic> {
+        try!(self.check_recursion(func, span));
+
+        let f = self.functions[&*func];
+        if self.shared.contains(&*func) {
+            let call = Expr { kind: ExprKind::Call(func.clone(), args), span: span };
+            return match f.ret {
+                Some(t) => {
+                    self.counter += 1;
+                    let result = format!("{}.{}", func, self.counter);
+                    pre.push(statement(StatementKind::Const(t, result.clone(), call), span));
+                    Ok(Some(Expr { kind: ExprKind::Var(result), span: span }))
+                },
+                None => {
+                    pre.push(statement(StatementKind::Expr(call), span));
+                    Ok(None)
+                }
+            };
+        }
+
+        self.counter += 1;
+        let n = self.counter;
+
+        let mut names = Substitution::new();
+        for (&(ref t, ref param), arg) in f.args.iter().zip(args.into_iter()) {
+            if substitutable(&arg) {
+                names.declare(param.clone(), arg);
+            } else {
+                let name = self.local(param, Some((func, n)));
+                names.declare(param.clone(), Expr { kind: ExprKind::Var(name.clone()), span: arg.span });
+                pre.push(statement(StatementKind::Const(*t, name, arg), span));
+            }
+        }
+
+        self.stack.push((func.clone(), span));
+        let body = try!(self.statements(&f.body, &mut names, Some((func, n))));
+        self.stack.pop();
+
+        let t = match f.ret {
+            Some(t) => t,
+            None => {
+                pre.extend(body);
+                return Ok(None);
+            }
+        };
+
+        // The result lives in a local of the caller
+        let result = format!("{}.{}", func, n);
+        let var = Expr { kind: ExprKind::Var(result.clone()), span: span };
+        let mut body = block(body, f.body.span);
+
+        // A single return at the very end just names the result
+        let single = match body.kind {
+            StatementKind::Block(ref mut stmts) => match stmts.last().map(|x| &x.kind) {
+                Some(&StatementKind::Return(_)) if !stmts[..stmts.len() - 1].iter().any(returns) => stmts.pop(),
+                _ => None
+            },
+            _ => None
+        };
+
+        if let Some(Statement { kind: StatementKind::Return(value), span: ret_span }) = single {
+            if let StatementKind::Block(stmts) = body.kind {
+                pre.extend(stmts);
+            }
+            pre.push(statement(StatementKind::Const(t, result, value), ret_span));
+            return Ok(Some(var));
+        }
+
+        // Otherwise every return stores into the result, and returns that
+        // would be followed by more statements break out of a loop instead
+        pre.push(statement(StatementKind::Mutable(t, result.clone(), default_value(&t, span)), span));
+        if translatable(&body, Position::Tail) {
+            pre.push(translate(body, &result, Position::Tail));
+        } else {
+            let wrapped = statement(StatementKind::Loop(Box::new(body)), f.body.span);
+            if !translatable(&wrapped, Position::Tail) {
+                return Err(Diagnostic::error(Code::CannotInline, span, format!("Function {} returns from inside a loop, so it cannot be inlined", func))
+                           .with_note(Some(f.span), format!("{} is declared here", func)));
+            }
+            pre.push(translate(wrapped, &result, Position::Tail));
+        }
+
+        Ok(Some(var))
+    }
+
+    // A statement on its own, as the branch of an if or an arm of an input
+    fn branch(&mut self, s : &Statement, names : &mut Substitution, copy : Option<(&Ident, u32)>) -> Result<Statement, Diagnostic> {
+        names.push();
+        let stmts = self.statements(s, names, copy);
+        names.pop();
+        Ok(block(try!(stmts), s.span))
+    }
+
+    // Rewrites a statement, returning it along with anything hoisted out of
+    // it.  The result is spliced into the enclosing block, so declarations
+    // stay visible to the statements after them.
+    fn statements(&mut self, s : &Statement, names : &mut Substitution, copy : Option<(&Ident, u32)>) -> Result<Vec<Statement>, Diagnostic> {
+        let mut out = vec![];
+        let kind = match &s.kind {
+            &StatementKind::Mutable(ref t, ref var, ref val) | &StatementKind::Const(ref t, ref var, ref val) => {
+                let val = try!(self.expr(val, names, &mut out));
+                let name = self.local(var, copy);
+                names.declare(var.clone(), Expr { kind: ExprKind::Var(name.clone()), span: s.span });
+
+                match &s.kind {
+                    &StatementKind::Mutable(_, _, _) => StatementKind::Mutable(*t, name, val),
+                    _ => StatementKind::Const(*t, name, val)
+                }
+            },
+            &StatementKind::Assign(ref var, ref val) => {
+                let val = try!(self.expr(val, names, &mut out));
+                StatementKind::Assign(self.target(var, names), val)
+            },
+            &StatementKind::Block(ref stmts) => {
+                names.push();
+                let mut body = vec![];
+                for stmt in stmts.iter() {
+                    match self.statements(stmt, names, copy) {
+                        Ok(x) => body.extend(x),
+                        Err(d) => {
+                            names.pop();
+                            return Err(d);
+                        }
+                    }
+                }
+                names.pop();
+                StatementKind::Block(body)
+            },
+            &StatementKind::Break => StatementKind::Break,
+            &StatementKind::If(ref cond, ref then, ref otherwise) => {
+                let cond = try!(self.expr(cond, names, &mut out));
+                let then = try!(self.branch(then, names, copy));
+                let otherwise = match otherwise {
+                    &Some(ref x) => Some(Box::new(try!(self.branch(x, names, copy)))),
+                    &None => None
+                };
+                StatementKind::If(cond, Box::new(then), otherwise)
+            },
+            &StatementKind::Input(ref branches) => {
+                let mut arms = vec![];
+                for &(key, ref arm) in branches.iter() {
+                    arms.push((key, try!(self.branch(arm, names, copy))));
+                }
+                StatementKind::Input(arms)
+            },
+            &StatementKind::Loop(ref body) => StatementKind::Loop(Box::new(try!(self.branch(body, names, copy)))),
+            &StatementKind::While(ref cond, ref body) => {
+                let mut pre = vec![];
+                let cond = try!(self.expr(cond, names, &mut pre));
+                let body = try!(self.branch(body, names, copy));
+                if pre.is_empty() {
+                    StatementKind::While(cond, Box::new(body))
+                } else {
+                    // Calls in the condition have to run before every check
+                    let span = cond.span;
+                    let exit = Expr { kind: ExprKind::Prefix(OpType::Not, Box::new(cond)), span: span };
+                    pre.push(statement(StatementKind::If(exit, Box::new(statement(StatementKind::Break, span)), None), span));
+                    pre.push(body);
+                    StatementKind::Loop(Box::new(statement(StatementKind::Block(pre), s.span)))
+                }
+            },
+            &StatementKind::Return(ref e) => StatementKind::Return(try!(self.expr(e, names, &mut out))),
+            &StatementKind::Expr(ref e) => {
+                // Calls to functions without a result are just their body
+                if let ExprKind::Call(ref func, ref args) = e.kind {
+                    if self.functions.get(&*func).map_or(false, |f| f.ret.is_none()) {
+                        let mut values = vec![];
+                        for arg in args.iter() {
+                            values.push(try!(self.expr(arg, names, &mut out)));
+                        }
+                        try!(self.call(func, values, e.span, &mut out));
+                        return Ok(out);
+                    }
+                }
+                StatementKind::Expr(try!(self.expr(e, names, &mut out)))
+            }
+        };
+
+        out.push(statement(kind, s.span));
+        Ok(out)
+    }
+
+    fn function(&mut self, f : &Function) -> Result<Function, Diagnostic> {
+        self.current = f.name.clone();
+        let mut names = Substitution::new();
+        let body = try!(self.statements(&f.body, &mut names, None));
+
+        Ok(Function {
+            ret: f.ret,
+            name: f.name.clone(),
+            args: f.args.clone(),
+            body: block(body, f.body.span),
+            span: f.span
+        })
+    }
+}
+
+// Inlines every call, leaving only main and the shared functions.  Every
+// function is still rewritten so that recursion is reported even if main
+// never reaches it.
+pub fn inline(t : &AST) -> Result<AST, Diagnostic> {
+    let mut inliner = Inliner {
+        functions: FunctionTable::new(),
+        shared: HashSet::new(),
+        current: String::new(),
+        stack: vec![],
+        counter: 0
+    };
+
+    for func in &t.0 {
+        inliner.functions.insert(func.name.clone(), func);
+    }
+
+    for func in &t.0 {
+        if shareable(func, &inliner.functions) {
+            inliner.shared.insert(func.name.clone());
+        }
+    }
+
+    let mut functions = vec![];
+    for func in &t.0 {
+        let inlined = try!(inliner.function(func));
+        if func.name == "main" || inliner.shared.contains(&func.name) {
+            functions.push(inlined);
+        }
+    }
+
+    Ok(AST(functions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser;
+
+    fn copied_codes(source : &str) -> Vec<Code> {
+        copied(&parser::parse(source.to_string()).unwrap()).iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn copied_functions() {
+        let ask = "int<0, 1> ask(string prompt) { say(prompt); input { W => return 1; S => return 0; } } ";
+        assert_eq!(copied_codes(&format!("{}main() {{ int<0, 1> a = ask(\"a\"); int<0, 1> b = ask(\"b\"); }}", ask)), vec![Code::CopiedFunction]);
+
+        // A single call costs nothing extra, and booleans are shared
+        assert_eq!(copied_codes(&format!("{}main() {{ int<0, 1> a = ask(\"a\"); }}", ask)), vec![]);
+        assert_eq!(copied_codes("bool f(bool b) { input { W => return b; } } main() { bool x = f(true); bool y = f(false); }"), vec![]);
+    }
+}
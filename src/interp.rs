@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::slice;
+
+use ast::*;
+use scope::Scopes;
+
+// A line of chat produced by the program
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Say(String),
+    SayTeam(String)
+}
+
+type FunctionTable<'a> = HashMap<Ident, &'a Function>;
+type Environment = Scopes<(Type, Value)>;
+
+// How control leaves a statement
+enum Flow {
+    Next,
+    Return(Value),
+    Break,
+    Halt // Ran out of key presses while waiting for input
+}
+
+// Loops that don't wait for input would overflow the console's alias stack,
+// so they're cut off here too
+static MAX_SPINS : usize = 256;
+
+struct Interpreter<'a, 'b> {
+    functions: FunctionTable<'a>,
+    keys: slice::Iter<'b, KeyType>,
+    messages: Vec<Message>,
+    halted: bool
+}
+
+pub fn parse_keys(keys : &str) -> Result<Vec<KeyType>, String> {
+    keys.split(',').filter(|x| !x.is_empty()).map(|key| match KeyType::named(&key.trim().to_uppercase()) {
+        Some(k) => Ok(k),
+        None => Err(format!("Unknown key {}", key.trim()))
+    }).collect()
+}
+
+fn check_range(name : &str, t : &Type, v : &Value) -> Result<(), String> {
+    match (t, v) {
+        (&Type::Int(low, high), &Value::Int(i)) if i < low || i > high =>
+            Err(format!("Value {} of {} is outside int<{}, {}>", i, name, low, high)),
+        _ => Ok(())
+    }
+}
+
+impl<'a, 'b> Interpreter<'a, 'b> {
+    fn eval(&mut self, e : &Expr, env : &mut Environment) -> Result<Value, String> {
+        match &e.kind {
+            &ExprKind::ConstBool(b) => Ok(Value::Bool(b)),
+            &ExprKind::ConstInt(i) => Ok(Value::Int(i)),
+            &ExprKind::ConstKey(k) => Ok(Value::Key(k)),
+            &ExprKind::ConstColor(c) => Ok(Value::Color(c)),
+            &ExprKind::ConstString(ref s) => Ok(Value::Str(s.clone())),
+            &ExprKind::ConstList(ref elems) => Ok(Value::List(elems.clone())),
+            &ExprKind::Var(ref name) => match env.get(&*name) {
+                Some(&(_, ref v)) => Ok(v.clone()),
+                None => Err(format!("Variable {} is referenced without being defined", name))
+            },
+            &ExprKind::Binop(ref l, ref o, ref r) => {
+                let l = try!(self.eval(l, env));
+
+                // Logical operators short-circuit
+                match (&l, o) {
+                    (&Value::Bool(false), &OpType::And) | (&Value::Bool(true), &OpType::Or) => return Ok(l),
+                    _ => ()
+                }
+
+                let r = try!(self.eval(r, env));
+                if self.halted {
+                    return Ok(Value::Bool(false));
+                }
+                eval_binop(l, *o, r)
+            },
+            &ExprKind::Unop(ref name, ref o) => {
+                let (low, high, i) = match env.get(&*name) {
+                    Some(&(Type::Int(low, high), Value::Int(i))) => (low, high, i),
+                    _ => return Err(format!("Unary operator {} can only be used on integers", o))
+                };
+
+                // Stepping past a bound wraps around, as it does in a config
+                let v = match o {
+                    &OpType::UPlus => Value::Int(wrap(i + 1, low, high)),
+                    &OpType::UMinus => Value::Int(wrap(i - 1, low, high)),
+                    _ => return Err(format!("Operator {} is not unary", o))
+                };
+                env.set(name, (Type::Int(low, high), v.clone()));
+                Ok(v)
+            },
+            &ExprKind::Prefix(ref o, ref e) => {
+                let v = try!(self.eval(e, env));
+                if self.halted {
+                    return Ok(Value::Bool(false));
+                }
+                eval_prefix(*o, v)
+            },
+            &ExprKind::Elem(ref list, ref index) => {
+                let list = try!(self.eval(list, env));
+                let index = try!(self.eval(index, env));
+                if self.halted {
+                    return Ok(Value::Bool(false));
+                }
+
+                match (list, index) {
+                    (Value::List(ref elems), Value::Int(i)) if i >= 0 && (i as usize) < elems.len() => Ok(Value::Str(elems[i as usize].clone())),
+                    (Value::List(ref elems), Value::Int(i)) => Err(format!("Index {} is outside a list of length {}", i, elems.len())),
+                    _ => Err("Only lists can be indexed into".to_string())
+                }
+            },
+            &ExprKind::Call(ref func, ref args) => {
+                let mut values = vec![];
+                for arg in args.iter() {
+                    values.push(try!(self.eval(arg, env)));
+                }
+
+                if self.halted {
+                    return Ok(Value::Bool(false));
+                }
+
+                // Each line of the text is sent as its own message
+                match &**func {
+                    "say" => {
+                        for line in values[0].to_string().split('\n') {
+                            self.messages.push(Message::Say(line.to_string()));
+                        }
+                        return Ok(Value::Bool(true));
+                    },
+                    "say_student" => {
+                        for line in values[0].to_string().split('\n') {
+                            self.messages.push(Message::SayTeam(line.to_string()));
+                        }
+                        return Ok(Value::Bool(true));
+                    },
+                    _ => ()
+                }
+
+                let f = match self.functions.get(&*func) {
+                    Some(f) => *f,
+                    None => return Err(format!("Function {} used without declaration", func))
+                };
+
+                let mut locals = Environment::new();
+                for (&(ref t, ref name), v) in f.args.iter().zip(values.into_iter()) {
+                    try!(check_range(name, t, &v));
+                    locals.declare(name.clone(), (*t, v));
+                }
+
+                match try!(self.exec(&f.body, &mut locals)) {
+                    Flow::Return(v) => {
+                        if let Some(ref t) = f.ret {
+                            try!(check_range(&format!("the result of {}", func), t, &v));
+                        }
+                        Ok(v)
+                    },
+                    // Results are discarded once we've halted
+                    _ => Ok(Value::Bool(false))
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, t : &Type, var : &Ident, val : &Expr, env : &mut Environment) -> Result<Flow, String> {
+        let v = try!(self.eval(val, env));
+        try!(check_range(var, t, &v));
+        env.declare(var.clone(), (*t, v));
+        Ok(self.flow())
+    }
+
+    // A declaration made by an arm or a loop body without braces is gone once
+    // it finishes, so the next pass through can make it again
+    fn exec_scoped(&mut self, s : &Statement, env : &mut Environment) -> Result<Flow, String> {
+        env.push();
+        let flow = self.exec(s, env);
+        env.pop();
+        flow
+    }
+
+    // Calls may halt in the middle of an expression
+    fn flow(&self) -> Flow {
+        if self.halted { Flow::Halt } else { Flow::Next }
+    }
+
+    // Runs body for as long as cond holds, or until it breaks
+    fn repeat(&mut self, cond : Option<&Expr>, body : &Statement, env : &mut Environment) -> Result<Flow, String> {
+        let mut spins = 0;
+        loop {
+            if let Some(cond) = cond {
+                let v = try!(self.eval(cond, env));
+                if self.halted {
+                    return Ok(Flow::Halt);
+                }
+
+                match v {
+                    Value::Bool(true) => (),
+                    Value::Bool(false) => return Ok(Flow::Next),
+                    _ => return Err("Conditions must be booleans".to_string())
+                }
+            }
+
+            let remaining = self.keys.len();
+            match try!(self.exec_scoped(body, env)) {
+                Flow::Next => (),
+                Flow::Break => return Ok(Flow::Next),
+                flow => return Ok(flow)
+            }
+
+            spins = if self.keys.len() == remaining { spins + 1 } else { 0 };
+            if spins > MAX_SPINS {
+                return Err(format!("Loop ran {} times without waiting for input", spins));
+            }
+        }
+    }
+
+    fn exec(&mut self, s : &Statement, env : &mut Environment) -> Result<Flow, String> {
+        match &s.kind {
+            &StatementKind::Mutable(ref t, ref var, ref val) => self.declare(t, var, val, env),
+            &StatementKind::Const(ref t, ref var, ref val) => self.declare(t, var, val, env),
+            &StatementKind::Assign(ref var, ref val) => {
+                let t = match env.get(&*var) {
+                    Some(&(t, _)) => t,
+                    None => return Err(format!("Undeclared variable {}", var))
+                };
+
+                let v = try!(self.eval(val, env));
+                try!(check_range(var, &t, &v));
+                env.set(var, (t, v));
+                Ok(self.flow())
+            },
+            &StatementKind::Block(ref stmts) => {
+                env.push();
+                let mut flow = Ok(Flow::Next);
+                for stmt in stmts.iter() {
+                    match self.exec(stmt, env) {
+                        Ok(Flow::Next) => (),
+                        result => {
+                            flow = result;
+                            break;
+                        }
+                    }
+                }
+
+                // Declarations end with the block
+                env.pop();
+                flow
+            },
+            &StatementKind::Break => Ok(Flow::Break),
+            &StatementKind::Loop(ref body) => self.repeat(None, body, env),
+            &StatementKind::While(ref cond, ref body) => self.repeat(Some(cond), body, env),
+            &StatementKind::If(ref cond, ref then, ref otherwise) => {
+                let v = try!(self.eval(cond, env));
+                if self.halted {
+                    return Ok(Flow::Halt);
+                }
+
+                match (v, otherwise) {
+                    (Value::Bool(true), _) => self.exec_scoped(then, env),
+                    (Value::Bool(false), &Some(ref otherwise)) => self.exec_scoped(otherwise, env),
+                    (Value::Bool(false), &None) => Ok(Flow::Next),
+                    _ => Err("Conditions must be booleans".to_string())
+                }
+            },
+            &StatementKind::Input(ref branches) => {
+                // Keys without an arm aren't bound, so pressing them does nothing
+                loop {
+                    let key = match self.keys.next() {
+                        Some(k) => k,
+                        None => {
+                            self.halted = true;
+                            return Ok(Flow::Halt);
+                        }
+                    };
+
+                    if let Some(&(_, ref arm)) = branches.iter().find(|&&(ref k, _)| k == key) {
+                        return self.exec_scoped(arm, env);
+                    }
+                }
+            },
+            &StatementKind::Return(ref expr) => {
+                let v = try!(self.eval(expr, env));
+                if self.halted {
+                    return Ok(Flow::Halt);
+                }
+                Ok(Flow::Return(v))
+            },
+            &StatementKind::Expr(ref expr) => {
+                try!(self.eval(expr, env));
+                Ok(self.flow())
+            }
+        }
+    }
+}
+
+// Runs main with a scripted sequence of key presses, returning the chat it
+// produced.  Execution stops once main returns or the keys run out.
+pub fn run(t : &AST, keys : &[KeyType]) -> Result<Vec<Message>, String> {
+    let mut interp = Interpreter {
+        functions: FunctionTable::new(),
+        keys: keys.iter(),
+        messages: vec![],
+        halted: false
+    };
+
+    for func in &t.0 {
+        interp.functions.insert(func.name.clone(), func);
+    }
+
+    let main = match interp.functions.get("main") {
+        Some(f) => *f,
+        None => return Err("Program has no main function".to_string())
+    };
+
+    try!(interp.exec(&main.body, &mut Environment::new()));
+    return Ok(interp.messages);
+}
+
+// Chat with its colour codes spelled out, since a terminal won't show them
+fn readable(text : &str) -> String {
+    text.chars().map(|c| match ColorType::coded(c) {
+        Some(color) => format!("{{{}}}", color.name()),
+        None => c.to_string()
+    }).collect()
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Message::Say(ref text) => write!(f, "say: {}", readable(text)),
+            &Message::SayTeam(ref text) => write!(f, "say_team: {}", readable(text))
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use ast::{ColorType, Ident, KeyType};
+use typechecker::unsafe_text;
+
+// The program as the console runs it: named states, each a list of primitive
+// actions.  Binding a key to a state is a transition taken when the key is
+// pressed.  Slots are names that actions point at a state while the program
+// runs, which is how runtime values pick what happens next.
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Say(String),
+    SayTeam(String),
+    Bind(KeyType, String),    // Pressing the key runs the state
+    SetAlias(String, String), // Points the slot at the state
+    Run(String),              // Runs a state, or whatever a slot points at
+    Game(String)              // A command the game runs itself, like casting
+}
+
+#[derive(Clone)]
+pub struct State {
+    pub name: String,
+    pub actions: Vec<Action>
+}
+
+// The states making up an integer variable, for reporting what it costs
+#[derive(Clone)]
+pub struct Variable {
+    pub name: Ident,
+    pub low: i32,
+    pub high: i32,
+    pub states: Vec<String>
+}
+
+#[derive(Clone)]
+pub struct Program {
+    pub states: Vec<State>,
+    pub entry: Vec<Action>, // Run once when the program is loaded
+    pub variables: Vec<Variable>
+}
+
+impl Action {
+    // The state or slot the action refers to, if any
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            &Action::Bind(_, ref name) | &Action::SetAlias(_, ref name) | &Action::Run(ref name) => Some(name),
+            _ => None
+        }
+    }
+
+    // Points the action at a different state or slot
+    pub fn retarget(&mut self, to : String) {
+        match self {
+            &mut Action::Bind(_, ref mut name) | &mut Action::SetAlias(_, ref mut name) | &mut Action::Run(ref mut name) => *name = to,
+            _ => ()
+        }
+    }
+}
+
+impl Program {
+    pub fn state(&self, name : &str) -> Option<&State> {
+        self.states.iter().find(|s| s.name == name)
+    }
+
+    // Names that are pointed at states while the program runs
+    pub fn slots(&self) -> HashSet<String> {
+        self.states.iter().flat_map(|s| s.actions.iter()).chain(self.entry.iter()).filter_map(|a| match a {
+            &Action::SetAlias(ref slot, _) => Some(slot.clone()),
+            _ => None
+        }).collect()
+    }
+}
+
+// Chat is emitted as it is, so nothing stands in for a control character and
+// a new line would end the alias definition.  Colour codes are the only
+// control characters allowed.  Literals are checked for the rest already, but
+// joining them can still spell out a //.
+fn check_text(text : &str) -> Result<(), String> {
+    if let Some(part) = unsafe_text(text) {
+        return Err(format!("Chat text {:?} cannot contain {}", text, part));
+    }
+    match text.chars().find(|&c| c.is_control() && ColorType::coded(c).is_none()) {
+        Some(c) => Err(format!("Chat text {:?} cannot contain {:?}", text, c)),
+        None => Ok(())
+    }
+}
+
+fn check_actions(actions : &[Action], owner : &str, states : &HashMap<&str, &State>, slots : &HashSet<String>) -> Result<(), String> {
+    for action in actions.iter() {
+        match action {
+            &Action::Say(ref text) | &Action::SayTeam(ref text) => try!(check_text(text)),
+            _ => ()
+        }
+
+        if let Some(target) = action.target() {
+            if !states.contains_key(target) && !slots.contains(target) {
+                return Err(format!("{} refers to {}, which is neither a state nor a slot", owner, target));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Finds states that run themselves without any slot in between, which the
+// console would recurse into until it gives up
+fn check_cycles<'a>(name : &'a str, states : &HashMap<&'a str, &'a State>, path : &mut Vec<&'a str>, done : &mut HashSet<&'a str>) -> Result<(), String> {
+    if done.contains(name) {
+        return Ok(());
+    }
+    if path.contains(&name) {
+        return Err(format!("States run each other forever: {} -> {}", path.join(" -> "), name));
+    }
+
+    path.push(name);
+    if let Some(state) = states.get(name) {
+        for action in state.actions.iter() {
+            if let &Action::Run(ref next) = action {
+                try!(check_cycles(next, states, path, done));
+            }
+        }
+    }
+    path.pop();
+    done.insert(name);
+    Ok(())
+}
+
+// Checks that the program can be emitted as a config and run
+pub fn validate(p : &Program) -> Result<(), String> {
+    let mut states = HashMap::new();
+    for state in p.states.iter() {
+        if states.insert(&*state.name, state).is_some() {
+            return Err(format!("State {} is defined twice", state.name));
+        }
+    }
+
+    let slots = p.slots();
+    if let Some(slot) = slots.iter().find(|s| states.contains_key(&***s)) {
+        return Err(format!("Slot {} is also defined as a state", slot));
+    }
+
+    for state in p.states.iter() {
+        try!(check_actions(&state.actions, &format!("State {}", state.name), &states, &slots));
+    }
+    try!(check_actions(&p.entry, "The entry", &states, &slots));
+
+    let mut done = HashSet::new();
+    for state in p.states.iter() {
+        try!(check_cycles(&state.name, &states, &mut vec![], &mut done));
+    }
+    Ok(())
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Action::Say(ref text) => write!(f, "say \"{}\"", text),
+            &Action::SayTeam(ref text) => write!(f, "say_team \"{}\"", text),
+            &Action::Bind(ref key, ref state) => write!(f, "on {:?} -> {}", key, state),
+            &Action::SetAlias(ref slot, ref state) => write!(f, "set {} -> {}", slot, state),
+            &Action::Run(ref state) => write!(f, "run {}", state),
+            &Action::Game(ref command) => write!(f, "game {}", command)
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for variable in self.variables.iter() {
+            try!(writeln!(f, "var {} int<{}, {}>: {}", variable.name, variable.low, variable.high, variable.states.join(" ")));
+        }
+
+        for state in self.states.iter() {
+            try!(writeln!(f, "state {}:", state.name));
+            for action in state.actions.iter() {
+                try!(writeln!(f, "    {}", action));
+            }
+        }
+
+        try!(writeln!(f, "entry:"));
+        for action in self.entry.iter() {
+            try!(writeln!(f, "    {}", action));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name : &str, actions : Vec<Action>) -> State {
+        State { name: name.to_string(), actions: actions }
+    }
+
+    fn program(states : Vec<State>, entry : Vec<Action>) -> Program {
+        Program { states: states, entry: entry, variables: vec![] }
+    }
+
+    fn run(name : &str) -> Action {
+        Action::Run(name.to_string())
+    }
+
+    #[test]
+    fn valid_program() {
+        let p = program(vec![state("a", vec![Action::SetAlias("s".to_string(), "b".to_string()), run("s")]), state("b", vec![run("s")])], vec![run("a")]);
+        assert_eq!(validate(&p), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_state() {
+        let p = program(vec![state("a", vec![]), state("a", vec![])], vec![]);
+        assert_eq!(validate(&p), Err("State a is defined twice".to_string()));
+    }
+
+    #[test]
+    fn slot_named_like_state() {
+        let p = program(vec![state("a", vec![]), state("b", vec![])], vec![Action::SetAlias("b".to_string(), "a".to_string())]);
+        assert_eq!(validate(&p), Err("Slot b is also defined as a state".to_string()));
+    }
+
+    #[test]
+    fn dangling_target() {
+        let p = program(vec![state("a", vec![run("c")])], vec![]);
+        assert_eq!(validate(&p), Err("State a refers to c, which is neither a state nor a slot".to_string()));
+
+        let p = program(vec![], vec![run("a")]);
+        assert_eq!(validate(&p), Err("The entry refers to a, which is neither a state nor a slot".to_string()));
+    }
+
+    #[test]
+    fn run_cycle() {
+        let p = program(vec![state("a", vec![run("b")]), state("b", vec![run("c")]), state("c", vec![run("a")])], vec![]);
+        assert_eq!(validate(&p), Err("States run each other forever: a -> b -> c -> a".to_string()));
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+
+use ast::*;
+use diagnostic::{Code, Diagnostic};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    // Literals
+    Bool(bool),
+    Int(i32), // Without its sign, which is a separate Minus
+    Str(String),
+    Ident(Ident),
+
+    // Keywords
+    If, Else, Loop, While, Input, Break, Return, Mut,
+    IntType, StringType, ColorType, KeyType, BoolType,
+
+    // Punctuation
+    LParen, RParen, LBrace, RBrace, LBracket, RBracket,
+    Comma, Semicolon, Assign, Arrow,
+    Plus, Minus, Times, Increment, Decrement,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    And, Or, Not,
+
+    Eof
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span
+}
+
+// Words that would otherwise be identifiers.  These only match whole words,
+// so Ammo and reduce are still identifiers.
+fn keyword(word : &str) -> Option<Token> {
+    Some(match word {
+        "true" => Token::Bool(true),
+        "false" => Token::Bool(false),
+        "if" => Token::If,
+        "else" => Token::Else,
+        "loop" => Token::Loop,
+        "while" => Token::While,
+        "input" => Token::Input,
+        "break" => Token::Break,
+        "return" => Token::Return,
+        "mut" => Token::Mut,
+        "int" => Token::IntType,
+        "string" => Token::StringType,
+        "color" => Token::ColorType,
+        "key" => Token::KeyType,
+        "bool" => Token::BoolType,
+        _ => return None
+    })
+}
+
+// Longer operators first, so < doesn't swallow the start of <=
+static PUNCTUATION : &'static [(&'static str, Token)] = &[
+    ("==", Token::Equal), ("!=", Token::NotEqual), ("<=", Token::LessEqual), (">=", Token::GreaterEqual),
+    ("&&", Token::And), ("||", Token::Or), ("=>", Token::Arrow), ("++", Token::Increment), ("--", Token::Decrement),
+    ("(", Token::LParen), (")", Token::RParen), ("{", Token::LBrace), ("}", Token::RBrace),
+    ("[", Token::LBracket), ("]", Token::RBracket), (",", Token::Comma), (";", Token::Semicolon),
+    ("=", Token::Assign), ("+", Token::Plus), ("-", Token::Minus), ("*", Token::Times),
+    ("<", Token::Less), (">", Token::Greater), ("!", Token::Not)
+];
+
+fn error(lo : usize, hi : usize, message : &str) -> Diagnostic {
+    Diagnostic::error(Code::SyntaxError, Span { lo: lo, hi: hi }, message.to_string())
+}
+
+// Skips whitespace and comments starting at the offset, collecting the
+// comments.  The offset is where the token before ended, if there is one.
+// Returns where the next token starts.
+fn trivia(source : &[u8], mut i : usize, mut after : Option<usize>, comments : &mut Vec<Comment>) -> Result<usize, Diagnostic> {
+    loop {
+        let rest = &source[i..];
+        if rest.first().map_or(false, |c| c.is_ascii_whitespace()) {
+            if rest[0] == b'\n' {
+                after = None;
+            }
+            i += 1;
+        } else if rest.starts_with(b"//") {
+            let end = rest.iter().position(|c| *c == b'\n').unwrap_or(rest.len());
+            comments.push(Comment {
+                span: Span { lo: i, hi: i + end },
+                text: String::from_utf8_lossy(&rest[2..end]).into_owned(),
+                block: false,
+                trails: after
+            });
+            i += end;
+        } else if rest.starts_with(b"/*") {
+            let end = match rest[2..].windows(2).position(|w| w == b"*/") {
+                Some(end) => end + 4,
+                None => return Err(error(i, i + 2, "Unterminated block comment"))
+            };
+            comments.push(Comment {
+                span: Span { lo: i, hi: i + end },
+                text: String::from_utf8_lossy(&rest[2..end - 2]).into_owned(),
+                block: true,
+                trails: after
+            });
+            if rest[..end].contains(&b'\n') {
+                after = None;
+            }
+            i += end;
+        } else {
+            return Ok(i);
+        }
+    }
+}
+
+// Reads a string literal starting at its opening quote, decoding escapes.
+// Literals end on the line they start on, so new lines have to be escaped.
+fn string(rest : &str, i : usize) -> Result<(Token, usize), Diagnostic> {
+    let mut text = String::new();
+    let mut chars = rest.char_indices().skip(1);
+
+    while let Some((j, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Token::Str(text), j + 1)),
+            '\\' => match chars.next() {
+                Some((_, '"')) => text.push('"'),
+                Some((_, '\\')) => text.push('\\'),
+                Some((_, 'n')) => text.push('\n'),
+                Some((k, c)) if !c.is_control() => return Err(error(i + j, i + k + c.len_utf8(), "Unknown escape sequence")),
+                _ => return Err(error(i + j, i + j + 1, "Unterminated string literal"))
+            },
+            '\n' | '\r' => return Err(error(i, i + j, "Unterminated string literal")),
+            c if c.is_control() => return Err(error(i + j, i + j + c.len_utf8(), "Strings cannot contain control characters")),
+            c => text.push(c)
+        }
+    }
+
+    Err(error(i, i + 1, "Unterminated string literal"))
+}
+
+// Writes text as a string literal the lexer would read back
+pub fn quote(text : &str) -> String {
+    let mut quoted = "\"".to_string();
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Reads the token at the offset, returning it and its length
+fn token(source : &str, i : usize) -> Result<(Token, usize), Diagnostic> {
+    let rest = &source[i..];
+    let bytes = rest.as_bytes();
+    let first = rest.chars().next().unwrap();
+
+    if first.is_ascii_alphabetic() {
+        let length = bytes.iter().position(|c| !c.is_ascii_alphanumeric() && *c != b'_').unwrap_or(bytes.len());
+        let word = &rest[..length];
+        return Ok((keyword(word).unwrap_or_else(|| Token::Ident(word.to_string())), length));
+    }
+
+    if first.is_ascii_digit() {
+        let length = bytes.iter().position(|c| !c.is_ascii_digit()).unwrap_or(bytes.len());
+        return match rest[..length].parse::<i32>() {
+            Ok(x) => Ok((Token::Int(x), length)),
+            Err(_) => Err(error(i, i + length, "Integer literal out of range"))
+        };
+    }
+
+    if first == '"' {
+        return string(rest, i);
+    }
+
+    for &(text, ref token) in PUNCTUATION.iter() {
+        if rest.starts_with(text) {
+            return Ok((token.clone(), text.len()));
+        }
+    }
+
+    Err(error(i, i + first.len_utf8(), "Unexpected character"))
+}
+
+// Splits the source into tokens, ending with Eof, along with its comments in
+// the order they appear
+pub fn lex(source : &str) -> Result<(Vec<Lexeme>, Vec<Comment>), Diagnostic> {
+    let mut lexemes = vec![];
+    let mut comments = vec![];
+    let mut i = 0;
+    let mut after = None;
+
+    loop {
+        i = try!(trivia(source.as_bytes(), i, after, &mut comments));
+        if i == source.len() {
+            lexemes.push(Lexeme { token: Token::Eof, span: Span { lo: i, hi: i } });
+            return Ok((lexemes, comments));
+        }
+
+        let (token, length) = try!(token(source, i));
+        lexemes.push(Lexeme { token: token, span: Span { lo: i, hi: i + length } });
+        i += length;
+        after = Some(i);
+    }
+}
+
+// Prints one token per line with where it came from
+pub struct Tokens<'a>(pub &'a [Lexeme]);
+
+impl<'a> fmt::Display for Tokens<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for lexeme in self.0.iter() {
+            try!(writeln!(f, "{}..{} {:?}", lexeme.span.lo, lexeme.span.hi, lexeme.token));
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use ast::*;
+use codegen::{default_bind, key_name};
+use ir::{Action, Program, State, Variable};
+use scope::Scopes;
+
+// Everything we generate is prefixed to avoid clobbering the user's aliases.
+// Generated names are joined with dots, which identifiers can't contain, and
+// fresh names lead with their number so that no two can run together.
+static PREFIX: &'static str = "cw";
+
+// A runtime integer, encoded as a ring of aliases with one setter per value.
+// Running a setter rebinds the increment/decrement aliases to its neighbours
+// and points every observer at the case for its value.  Booleans are rings of
+// 0 for false and 1 for true.
+struct StateVar {
+    name: Ident,
+    prefix: String,
+    low: i32,
+    high: i32,
+    boolean: bool,
+    observers: Vec<String>,
+    cases: Vec<State>
+}
+
+#[derive(Clone)]
+enum Binding {
+    Known(Value),
+    State(usize)
+}
+
+// How a function kept whole by the inliner is called.  Arguments and the
+// result pass through state variables, and each caller points the return
+// slot at an alias that resumes it.
+#[derive(Clone)]
+struct Shared {
+    params: Vec<usize>,
+    result: Option<usize>,
+    slot: String
+}
+
+type FunctionTable<'a> = HashMap<Ident, &'a Function>;
+type Environment = Scopes<Binding>;
+
+struct Generator<'a> {
+    functions: FunctionTable<'a>,
+    lowered: Vec<State>, // Every state generated so far
+    states: Vec<StateVar>,
+    loops: Vec<Option<String>>, // Where break continues for each enclosing loop
+    shared: HashMap<Ident, Shared>,
+    current: Option<Shared>, // The shared function being lowered, if any
+    restored: Vec<KeyType>, // Keys whose restore alias is set up on entry
+    counter: u32
+}
+
+fn function_alias(name : &str) -> String {
+    format!("{}.{}", PREFIX, name)
+}
+
+// The console can't tell what a key was bound to, so once an input is over
+// its keys are bound to an alias per key instead.  The config points it at
+// what the game binds the key to, and an autoexec run after the config can
+// redefine it to give the key back a bind of the player's own.
+pub fn restore_alias(key : &KeyType) -> String {
+    format!("{}.key.{}", PREFIX, key_name(key).replace("+", "_"))
+}
+
+// Names the alias for one value of a variable.  Alias names can't contain
+// a minus, so negative values are written with an n.
+fn setter(prefix : &str, v : i32) -> String {
+    if v < 0 {
+        format!("{}.n{}", prefix, -v)
+    } else {
+        format!("{}.{}", prefix, v)
+    }
+}
+
+impl StateVar {
+    fn values(&self) -> Range<i32> {
+        self.low..self.high + 1
+    }
+
+    fn value(&self, v : i32) -> Value {
+        if self.boolean { Value::Bool(v == 1) } else { Value::Int(v) }
+    }
+
+    fn setters(&self) -> Vec<State> {
+        self.values().map(|v| {
+            let mut actions = vec![
+                Action::SetAlias(format!("{}.inc", self.prefix), setter(&self.prefix, wrap(v + 1, self.low, self.high))),
+                Action::SetAlias(format!("{}.dec", self.prefix), setter(&self.prefix, wrap(v - 1, self.low, self.high)))
+            ];
+
+            for observer in self.observers.iter() {
+                actions.push(Action::SetAlias(observer.clone(), setter(observer, v)));
+            }
+
+            State { name: setter(&self.prefix, v), actions: actions }
+        }).collect()
+    }
+}
+
+// Finds a variable whose value is only known at runtime
+fn find_state(e : &Expr, env : &Environment) -> Option<(Ident, usize)> {
+    match &e.kind {
+        &ExprKind::Var(ref name) | &ExprKind::Unop(ref name, _) => match env.get(&*name) {
+            Some(&Binding::State(i)) => Some((name.clone(), i)),
+            _ => None
+        },
+        &ExprKind::Binop(ref l, _, ref r) | &ExprKind::Elem(ref l, ref r) => find_state(l, env).or_else(|| find_state(r, env)),
+        &ExprKind::Call(_, ref args) => args.iter().filter_map(|x| find_state(x, env)).next(),
+        &ExprKind::Prefix(_, ref e) => find_state(e, env),
+        _ => None
+    }
+}
+
+// Whether control may leave the statement waiting for a key press, or
+// jump elsewhere without falling through to the statements after it
+fn suspends(s : &Statement) -> bool {
+    match &s.kind {
+        &StatementKind::Input(_) | &StatementKind::Return(_) | &StatementKind::Break => true,
+        &StatementKind::Expr(ref e) | &StatementKind::Const(_, _, ref e) => calls_function(e),
+        &StatementKind::Loop(_) | &StatementKind::While(_, _) => true,
+        &StatementKind::Block(ref stmts) => stmts.iter().any(suspends),
+        &StatementKind::If(_, ref then, ref otherwise) => suspends(then) || otherwise.as_ref().map_or(false, |x| suspends(x)),
+        _ => false
+    }
+}
+
+// A new scope for a block or arm.  Each declaration gets aliases of its own,
+// so names from finished scopes can be reused without clashing.
+fn scoped(env : &Environment) -> Environment {
+    let mut inner = env.clone();
+    inner.push();
+    inner
+}
+
+// Calls left by the inliner return through a continuation
+fn calls_function(e : &Expr) -> bool {
+    match &e.kind {
+        &ExprKind::Call(ref func, _) => builtin_command(func).is_none(),
+        _ => false
+    }
+}
+
+// Maps chat builtins onto the action implementing them
+fn builtin_command(name : &str) -> Option<fn(String) -> Action> {
+    match name {
+        "say" => Some(Action::Say),
+        "say_student" => Some(Action::SayTeam),
+        _ => None
+    }
+}
+
+impl<'a> Generator<'a> {
+    fn fresh(&mut self, base : &str) -> String {
+        self.counter += 1;
+        format!("{}.{}.{}", PREFIX, self.counter, base)
+    }
+
+    // Gives a key back the bind it had before an input.  Keys the game
+    // doesn't bind do nothing until an autoexec says what they did.
+    fn restore(&mut self, key : KeyType) -> Action {
+        let slot = restore_alias(&key);
+        if !self.restored.contains(&key) {
+            let actions = default_bind(&key).map(|command| Action::Game(command.to_string())).into_iter().collect();
+            self.lowered.push(State { name: format!("{}.default", slot), actions: actions });
+            self.restored.push(key);
+        }
+        Action::Bind(key, slot)
+    }
+
+    fn declare_state(&mut self, t : &Type, var : &Ident) -> Result<usize, String> {
+        let (low, high, boolean) = match t {
+            &Type::Int(low, high) if low <= high => (low, high, false),
+            &Type::Int(_, _) => return Err(format!("Variable {} has no possible values", var)),
+            &Type::Bool => (0, 1, true),
+            t => return Err(format!("Variable {} holds a {} only known while the program runs, but only integers and booleans can be", var, t))
+        };
+
+        let prefix = self.fresh(var);
+        self.states.push(StateVar {
+            name: var.clone(),
+            prefix: prefix,
+            low: low,
+            high: high,
+            boolean: boolean,
+            observers: vec![],
+            cases: vec![]
+        });
+        Ok(self.states.len() - 1)
+    }
+
+    // Stores the value of e into a state variable
+    fn lower_store(&mut self, index : usize, e : &Expr, env : &Environment, out : &mut Vec<Action>) -> Result<(), String> {
+        let (prefix, low, high) = {
+            let state = &self.states[index];
+            (state.prefix.clone(), state.low, state.high)
+        };
+
+        self.lower_value(e, env, out, &move |v| match v {
+            Value::Int(i) => Ok(vec![Action::Run(setter(&prefix, wrap(i, low, high)))]),
+            Value::Bool(b) => Ok(vec![Action::Run(setter(&prefix, b as i32))]),
+            _ => Err("Only integers and booleans can be stored in state variables".to_string())
+        })
+    }
+
+    // Emits the actions built from the value of e, which may depend on
+    // runtime state.  Modifications run first, leaving their variable to be
+    // read in their place, which the typechecker makes sure can't be told
+    // apart from running them where they are.
+    fn lower_value(&mut self, e : &Expr, env : &Environment, out : &mut Vec<Action>,
+                   emit : &dyn Fn(Value) -> Result<Vec<Action>, String>) -> Result<(), String> {
+        let e = try!(self.hoist(e, env, out));
+        self.lower_cases(&e, env, out, emit)
+    }
+
+    fn hoist(&mut self, e : &Expr, env : &Environment, out : &mut Vec<Action>) -> Result<Expr, String> {
+        let kind = match &e.kind {
+            &ExprKind::Unop(ref name, _) => {
+                try!(self.lower_expr(e, env, out));
+                ExprKind::Var(name.clone())
+            },
+            &ExprKind::Binop(ref l, o, ref r) => ExprKind::Binop(Box::new(try!(self.hoist(l, env, out))), o, Box::new(try!(self.hoist(r, env, out)))),
+            &ExprKind::Elem(ref l, ref r) => ExprKind::Elem(Box::new(try!(self.hoist(l, env, out))), Box::new(try!(self.hoist(r, env, out)))),
+            &ExprKind::Prefix(o, ref operand) => ExprKind::Prefix(o, Box::new(try!(self.hoist(operand, env, out)))),
+            &ExprKind::Call(ref func, ref args) => {
+                let mut values = vec![];
+                for arg in args.iter() {
+                    values.push(try!(self.hoist(arg, env, out)));
+                }
+                ExprKind::Call(func.clone(), values)
+            },
+            kind => kind.clone()
+        };
+        Ok(Expr { kind: kind, span: e.span })
+    }
+
+    // Each runtime variable in e is resolved by an observer that dispatches
+    // to a separately generated case for every possible value
+    fn lower_cases(&mut self, e : &Expr, env : &Environment, out : &mut Vec<Action>,
+                   emit : &dyn Fn(Value) -> Result<Vec<Action>, String>) -> Result<(), String> {
+        let (var, index) = match find_state(e, env) {
+            Some(x) => x,
+            None => {
+                let v = try!(self.eval(e, env));
+                out.extend(try!(emit(v)));
+                return Ok(());
+            }
+        };
+
+        let observer = self.fresh(&format!("{}_observe", var));
+        let mut cases = vec![];
+        for v in self.states[index].values() {
+            let mut env = env.clone();
+            env.set(&var, Binding::Known(self.states[index].value(v)));
+
+            let mut actions = vec![];
+            try!(self.lower_cases(e, &env, &mut actions, emit));
+            cases.push(State { name: setter(&observer, v), actions: actions });
+        }
+
+        let state = &mut self.states[index];
+        state.observers.push(observer.clone());
+        state.cases.extend(cases);
+        out.push(Action::Run(observer));
+        Ok(())
+    }
+
+    // Evaluates an expression whose value must be known at compile time
+    fn eval(&self, e : &Expr, env : &Environment) -> Result<Value, String> {
+        match &e.kind {
+            &ExprKind::ConstBool(b) => Ok(Value::Bool(b)),
+            &ExprKind::ConstInt(i) => Ok(Value::Int(i)),
+            &ExprKind::ConstKey(k) => Ok(Value::Key(k)),
+            &ExprKind::ConstColor(c) => Ok(Value::Color(c)),
+            &ExprKind::ConstString(ref s) => Ok(Value::Str(s.clone())),
+            &ExprKind::ConstList(ref elems) => Ok(Value::List(elems.clone())),
+            &ExprKind::Var(ref name) => match env.get(&*name) {
+                Some(&Binding::Known(ref v)) => Ok(v.clone()),
+                _ => Err(format!("Value of {} is not known at compile time", name))
+            },
+            &ExprKind::Binop(ref l, ref o, ref r) => {
+                let l = try!(self.eval(l, env));
+                let r = try!(self.eval(r, env));
+                eval_binop(l, *o, r)
+            },
+            &ExprKind::Prefix(ref o, ref e) => eval_prefix(*o, try!(self.eval(e, env))),
+            &ExprKind::Elem(ref list, ref index) => {
+                match (try!(self.eval(list, env)), try!(self.eval(index, env))) {
+                    (Value::List(ref elems), Value::Int(i)) if i >= 0 && (i as usize) < elems.len() => Ok(Value::Str(elems[i as usize].clone())),
+                    _ => Err(format!("Invalid list index {}", e))
+                }
+            },
+            &ExprKind::Unop(ref name, _) => Err(format!("Modification of {} cannot be used as a value", name)),
+            &ExprKind::Call(ref func, _) => Err(format!("Function {} has no value to use", func))
+        }
+    }
+
+    // Lowers an expression evaluated only for its side-effects
+    fn lower_expr(&mut self, e : &Expr, env : &Environment, out : &mut Vec<Action>) -> Result<(), String> {
+        match &e.kind {
+            &ExprKind::Call(ref func, ref args) => {
+                if let Some(command) = builtin_command(func) {
+                    // Chat can't span lines, so each line is a message
+                    return self.lower_value(&args[0], env, out, &move |text| Ok(text.to_string().split('\n').map(|line| command(line.to_string())).collect()));
+                }
+
+                Err(format!("Call to {} was not inlined", func))
+            },
+            &ExprKind::Unop(ref name, ref o) => {
+                let prefix = match env.get(&*name) {
+                    Some(&Binding::State(i)) => self.states[i].prefix.clone(),
+                    _ => return Err(format!("Variable {} is not mutable", name))
+                };
+
+                match o {
+                    &OpType::UPlus => out.push(Action::Run(format!("{}.inc", prefix))),
+                    &OpType::UMinus => out.push(Action::Run(format!("{}.dec", prefix))),
+                    _ => return Err(format!("Operator {} is not unary", o))
+                }
+                Ok(())
+            },
+            &ExprKind::Binop(ref l, _, ref r) | &ExprKind::Elem(ref l, ref r) => {
+                try!(self.lower_expr(l, env, out));
+                self.lower_expr(r, env, out)
+            },
+            &ExprKind::Prefix(_, ref e) => self.lower_expr(e, env, out),
+            _ => Ok(())
+        }
+    }
+
+    // Lowers stmts, then runs the continuation k.  Statements that wait for
+    // input cannot fall through, so everything after them is moved into a
+    // separate alias that they resume once they're done.
+    fn lower_block(&mut self, stmts : &[Statement], env : &mut Environment, out : &mut Vec<Action>, k : Option<&str>) -> Result<(), String> {
+        for (i, stmt) in stmts.iter().enumerate() {
+            if let &StatementKind::Return(_) = &stmt.kind {
+                return self.lower_statement(stmt, env, out, None);
+            }
+
+            if suspends(stmt) {
+                let rest = &stmts[i + 1..];
+                if rest.is_empty() {
+                    return self.lower_statement(stmt, env, out, k);
+                }
+
+                let resume = self.fresh("resume");
+                try!(self.lower_statement(stmt, env, out, Some(&resume)));
+
+                let mut actions = vec![];
+                try!(self.lower_block(rest, env, &mut actions, k));
+                self.lowered.push(State { name: resume, actions: actions });
+                return Ok(());
+            }
+
+            try!(self.lower_statement(stmt, env, out, None));
+        }
+
+        if let Some(k) = k {
+            out.push(Action::Run(k.to_string()));
+        }
+        Ok(())
+    }
+
+    fn lower_statement(&mut self, s : &Statement, env : &mut Environment, out : &mut Vec<Action>, k : Option<&str>) -> Result<(), String> {
+        match &s.kind {
+            &StatementKind::Block(ref stmts) => return self.lower_block(stmts, &mut scoped(env), out, k),
+            &StatementKind::Input(ref branches) => {
+                // Each arm gives the input's keys back their binds before
+                // running, so nested inputs bind over the state that preceded
+                // the outer input.
+                let restore : Vec<_> = branches.iter().map(|&(key, _)| self.restore(key)).collect();
+
+                for &(key, ref arm) in branches.iter() {
+                    let name = self.fresh(&format!("input_{}", key_name(&key).replace("+", "_")));
+                    let mut actions = restore.clone();
+                    try!(self.lower_statement(arm, &mut scoped(env), &mut actions, k));
+
+                    self.lowered.push(State { name: name.clone(), actions: actions });
+                    out.push(Action::Bind(key, name));
+                }
+                return Ok(());
+            },
+            &StatementKind::If(ref cond, ref then, ref otherwise) => {
+                if find_state(cond, env).is_none() {
+                    let taken = match try!(self.eval(cond, env)) {
+                        Value::Bool(true) => Some(&**then),
+                        Value::Bool(false) => otherwise.as_ref().map(|x| &**x),
+                        _ => return Err("Conditions must be booleans".to_string())
+                    };
+
+                    if let Some(taken) = taken {
+                        return self.lower_statement(taken, &mut scoped(env), out, k);
+                    }
+                } else {
+                    // Each branch gets an alias, and an observer picks one
+                    let branches = vec![Some(&**then), otherwise.as_ref().map(|x| &**x)];
+                    let mut names = vec![];
+                    for branch in branches {
+                        let name = self.fresh("branch");
+                        let mut actions = vec![];
+                        match branch {
+                            Some(branch) => try!(self.lower_statement(branch, &mut scoped(env), &mut actions, k)),
+                            None => actions.extend(k.map(|k| Action::Run(k.to_string())))
+                        }
+
+                        self.lowered.push(State { name: name.clone(), actions: actions });
+                        names.push(name);
+                    }
+
+                    return self.lower_value(cond, env, out, &move |v| match v {
+                        Value::Bool(true) => Ok(vec![Action::Run(names[0].clone())]),
+                        Value::Bool(false) => Ok(vec![Action::Run(names[1].clone())]),
+                        _ => Err("Conditions must be booleans".to_string())
+                    });
+                }
+            },
+            &StatementKind::Loop(ref body) => {
+                // A loop re-enters itself immediately, so it has to stop somewhere
+                if !suspends(body) {
+                    return Err("Loop never waits for input or breaks, so it would run forever".to_string());
+                }
+
+                let start = self.fresh("loop");
+                let actions = try!(self.lower_loop_body(body, env, &start, k));
+                self.lowered.push(State { name: start.clone(), actions: actions });
+                out.push(Action::Run(start));
+                return Ok(());
+            },
+            &StatementKind::While(ref cond, ref body) => {
+                let start = self.fresh("while");
+                let name = self.fresh("body");
+                let actions = try!(self.lower_loop_body(body, env, &start, k));
+                self.lowered.push(State { name: name.clone(), actions: actions });
+
+                // The condition is checked again before every iteration
+                let exit : Vec<Action> = k.iter().map(|k| Action::Run(k.to_string())).collect();
+                let mut actions = vec![];
+                try!(self.lower_value(cond, env, &mut actions, &move |v| match v {
+                    Value::Bool(true) => Ok(vec![Action::Run(name.clone())]),
+                    Value::Bool(false) => Ok(exit.clone()),
+                    _ => Err("Conditions must be booleans".to_string())
+                }));
+
+                self.lowered.push(State { name: start.clone(), actions: actions });
+                out.push(Action::Run(start));
+                return Ok(());
+            },
+            &StatementKind::Break => {
+                match self.loops.last() {
+                    Some(exit) => out.extend(exit.iter().map(|k| Action::Run(k.clone()))),
+                    None => return Err("Break used outside of a loop".to_string())
+                }
+                return Ok(());
+            },
+            &StatementKind::Expr(Expr { kind: ExprKind::Call(ref func, ref args), .. }) if builtin_command(func).is_none() =>
+                return self.lower_call(func, args, None, env, out, k),
+            &StatementKind::Const(ref t, ref var, Expr { kind: ExprKind::Call(ref func, ref args), .. }) if builtin_command(func).is_none() =>
+                return self.lower_call(func, args, Some((t, var)), env, out, k),
+            &StatementKind::Return(ref expr) => {
+                return match self.current.clone() {
+                    Some(shared) => {
+                        if let Some(index) = shared.result {
+                            try!(self.lower_store(index, expr, env, out));
+                        }
+                        out.push(Action::Run(shared.slot));
+                        Ok(())
+                    },
+                    None => self.lower_expr(expr, env, out)
+                };
+            },
+            &StatementKind::Const(ref t, ref var, ref val) => {
+                if find_state(val, env).is_none() {
+                    let v = try!(self.eval(val, env));
+                    env.declare(var.clone(), Binding::Known(v));
+                } else {
+                    // Constants computed from runtime values need their own state
+                    let index = try!(self.declare_state(t, var));
+                    try!(self.lower_store(index, val, env, out));
+                    env.declare(var.clone(), Binding::State(index));
+                }
+            },
+            &StatementKind::Mutable(ref t, ref var, ref val) => {
+                let index = try!(self.declare_state(t, var));
+                try!(self.lower_store(index, val, env, out));
+                env.declare(var.clone(), Binding::State(index));
+            },
+            &StatementKind::Assign(ref var, ref val) => {
+                match env.get(&*var) {
+                    Some(&Binding::State(index)) => try!(self.lower_store(index, val, env, out)),
+                    _ => return Err(format!("Variable {} is not mutable", var))
+                }
+            },
+            &StatementKind::Expr(ref expr) => try!(self.lower_expr(expr, env, out))
+        }
+
+        if let Some(k) = k {
+            out.push(Action::Run(k.to_string()));
+        }
+        Ok(())
+    }
+
+    // Lowers the body of a loop starting at the alias start, which it runs
+    // again once it's done.  Breaking out of it continues with k.
+    fn lower_loop_body(&mut self, body : &Statement, env : &Environment, start : &str, k : Option<&str>) -> Result<Vec<Action>, String> {
+        let mut actions = vec![];
+        self.loops.push(k.map(|k| k.to_string()));
+        let result = self.lower_statement(body, &mut scoped(env), &mut actions, Some(start));
+        self.loops.pop();
+
+        try!(result);
+        Ok(actions)
+    }
+
+    // Calls a shared function, storing its result into var once it returns
+    // and then continuing with k
+    fn lower_call(&mut self, func : &Ident, args : &[Expr], result : Option<(&Type, &Ident)>,
+                  env : &mut Environment, out : &mut Vec<Action>, k : Option<&str>) -> Result<(), String> {
+        let shared = match self.shared.get(&*func) {
+            Some(shared) => shared.clone(),
+            None => return Err(format!("Function {} used without declaration", func))
+        };
+
+        for (&index, arg) in shared.params.iter().zip(args.iter()) {
+            try!(self.lower_store(index, arg, env, out));
+        }
+
+        // Copy the result out, since the next call will overwrite it
+        let name = self.fresh("return");
+        let mut actions = vec![];
+        if let (Some((t, var)), Some(from)) = (result, shared.result) {
+            let index = try!(self.declare_state(t, var));
+            let mut callee = Environment::new();
+            callee.declare(var.clone(), Binding::State(from));
+            try!(self.lower_store(index, &Expr { kind: ExprKind::Var(var.clone()), span: Span::default() }, &callee, &mut actions));
+            env.declare(var.clone(), Binding::State(index));
+        }
+        actions.extend(k.map(|k| Action::Run(k.to_string())));
+        self.lowered.push(State { name: name.clone(), actions: actions });
+
+        out.push(Action::SetAlias(shared.slot, name));
+        out.push(Action::Run(function_alias(func)));
+        Ok(())
+    }
+
+    fn lower_function(&mut self, f : &Function) -> Result<(), String> {
+        let mut env = Environment::new();
+        let mut actions = vec![];
+
+        // Shared functions run their caller's continuation when they finish
+        self.current = self.shared.get(&f.name).cloned();
+        let slot = self.current.as_ref().map(|shared| shared.slot.clone());
+        if let Some(ref shared) = self.current {
+            for (&(_, ref param), &index) in f.args.iter().zip(shared.params.iter()) {
+                env.declare(param.clone(), Binding::State(index));
+            }
+        }
+
+        try!(self.lower_statement(&f.body, &mut env, &mut actions, slot.as_ref().map(|x| &**x)));
+
+        self.lowered.push(State { name: function_alias(&f.name), actions: actions });
+        Ok(())
+    }
+}
+
+// Lowers a typechecked and inlined program into states
+pub fn lower(t : &AST) -> Result<Program, String> {
+    let mut gen = Generator {
+        functions: FunctionTable::new(),
+        lowered: vec![],
+        states: vec![],
+        loops: vec![],
+        shared: HashMap::new(),
+        current: None,
+        restored: vec![],
+        counter: 0
+    };
+
+    for func in &t.0 {
+        gen.functions.insert(func.name.clone(), func);
+    }
+
+    if !gen.functions.contains_key("main") {
+        return Err("Program has no main function".to_string());
+    }
+
+    // Everything but main was kept by the inliner to be called through a
+    // continuation
+    for func in t.0.iter().filter(|f| f.name != "main") {
+        let mut params = vec![];
+        for &(ref t, ref param) in func.args.iter() {
+            params.push(try!(gen.declare_state(t, &format!("{}.{}", func.name, param))));
+        }
+
+        let result = match func.ret {
+            Some(ref t) => Some(try!(gen.declare_state(t, &format!("{}.result", func.name)))),
+            None => None
+        };
+
+        let slot = format!("{}.return", function_alias(&func.name));
+        gen.shared.insert(func.name.clone(), Shared { params: params, result: result, slot: slot });
+    }
+
+    for func in &t.0 {
+        try!(gen.lower_function(func));
+    }
+
+    let mut entry : Vec<Action> = gen.restored.iter().map(|key| {
+        let slot = restore_alias(key);
+        Action::SetAlias(slot.clone(), format!("{}.default", slot))
+    }).collect();
+    entry.push(Action::Run(function_alias("main")));
+
+    let mut states = gen.lowered;
+    let mut variables = vec![];
+    for state in gen.states {
+        let mut state_aliases = state.setters();
+        state_aliases.extend(state.cases);
+
+        variables.push(Variable {
+            name: state.name,
+            low: state.low,
+            high: state.high,
+            states: state_aliases.iter().map(|s| s.name.clone()).collect()
+        });
+        states.extend(state_aliases);
+    }
+
+    return Ok(Program { states: states, entry: entry, variables: variables });
+}
@@ -6,16 +6,130 @@ extern crate getopts;
 use getopts::Options;
 
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::fmt::Display;
 use std::fs::File;
+use std::path::Path;
 use std::env;
+use std::io;
+use std::process;
 
 mod ast;
+mod codegen;
+mod diagnostic;
+mod inline;
+mod interp;
+mod lexer;
+mod ir;
+mod lower;
+mod optimize;
 mod parser;
+mod resolve;
+mod scope;
 mod typechecker;
+mod vm;
 
-fn print_usage(program: &str, opts: Options) {
+// Stages of compilation that can be written out, in pipeline order
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Tokens,
+    Ast,
+    Typed,
+    Inlined,
+    Ir,
+    OptimizedIr,
+    Cfg
+}
+
+static STAGES : &'static [(&'static str, Stage)] = &[
+    ("tokens", Stage::Tokens),
+    ("ast", Stage::Ast),
+    ("typed", Stage::Typed),
+    ("inlined", Stage::Inlined),
+    ("ir", Stage::Ir),
+    ("opt-ir", Stage::OptimizedIr),
+    ("cfg", Stage::Cfg)
+];
+
+fn stage_name(stage : Stage) -> &'static str {
+    STAGES.iter().find(|&&(_, s)| s == stage).unwrap().0
+}
+
+fn parse_stages(list : &str) -> Result<Vec<Stage>, String> {
+    list.split(',').map(|name| match STAGES.iter().find(|&&(n, _)| n == name.trim()) {
+        Some(&(_, stage)) => Ok(stage),
+        None => Err(format!("Unknown stage {}, expected one of {}", name, STAGES.iter().map(|x| x.0).collect::<Vec<_>>().join(", ")))
+    }).collect()
+}
+
+// Where the requested stages are written.  With several stages and a path,
+// each stage gets the path with its name as the extension.
+struct Output {
+    stages: Vec<Stage>,
+    path: Option<String>,
+    color: bool,
+    run: bool,     // Running needs the typed AST and simulating needs the
+    simulate: bool // program, whatever else was asked for
+}
+
+impl Output {
+    fn wants(&self, stage : Stage) -> bool {
+        self.stages.contains(&stage)
+    }
+
+    // Whether nothing after this stage is needed
+    fn last(&self, stage : Stage) -> bool {
+        let index = |s : Stage| STAGES.iter().position(|&(_, x)| x == s).unwrap();
+        let needed = if self.simulate {
+            Some(Stage::OptimizedIr)
+        } else if self.run {
+            Some(Stage::Typed)
+        } else {
+            None
+        };
+        self.stages.iter().all(|&s| index(s) <= index(stage)) && needed.map_or(true, |s| index(s) < index(stage))
+    }
+
+    fn write(&self, stage : Stage, contents : &dyn Display) -> Result<(), String> {
+        let path = match self.path {
+            Some(ref path) if self.stages.len() == 1 => Path::new(path).to_path_buf(),
+            Some(ref path) => Path::new(path).with_extension(stage_name(stage)),
+            None => {
+                print!("{}", contents);
+                return Ok(());
+            }
+        };
+
+        // Files are never read in a terminal
+        ast::set_color(false);
+        let text = contents.to_string();
+        ast::set_color(self.color);
+
+        File::create(&path).and_then(|mut f| f.write_all(text.as_bytes()))
+            .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+    }
+
+    // Writes the stage if it was asked for, returning whether to stop here
+    fn emit(&self, stage : Stage, contents : &dyn Display) -> bool {
+        if self.wants(stage) {
+            if let Err(s) = self.write(stage, contents) {
+                fail(&s);
+            }
+        }
+        self.last(stage)
+    }
+}
+
+fn usage(program: &str, opts: &Options) -> String {
     let brief = format!("Usage: {} [options] FILE", program);
-    print!("{}", opts.usage(&brief));
+    opts.usage(&brief)
+}
+
+// Reports an error where it won't end up in the emitted output, failing so
+// scripts can tell nothing was written
+fn fail(message : &dyn Display) -> ! {
+    eprintln!("{}", message.to_string().trim_end());
+    process::exit(1);
 }
 
 fn initialize_args() -> Options {
@@ -23,7 +137,11 @@ fn initialize_args() -> Options {
 
     opts.optopt("O", "", "set optimization level", "[0-3]");
     opts.optflag("h", "help", "print this help menu");
-    opts.optflag("", "ast", "print the ast");
+    opts.optopt("", "run", "interpret the program with scripted key presses", "W,A,S,D");
+    opts.optopt("", "simulate", "run the generated config with scripted key presses", "W,A,S,D");
+    opts.optopt("", "emit", "stages to write: tokens, ast, typed, inlined, ir, opt-ir or cfg", "STAGE[,STAGE]");
+    opts.optflag("", "ast", "write the AST, the same as --emit ast");
+    opts.optopt("o", "", "write the emitted stages to a file", "PATH");
 
     return opts;
 }
@@ -36,44 +154,200 @@ fn main() {
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => fail(&format!("{}\n{}", f, usage(&program, &opts)))
     };
 
     if matches.opt_present("h") {
-        print_usage(&program, opts);
+        print!("{}", usage(&program, &opts));
         return;
     }
 
-    let print_ast = matches.opt_present("ast");
+    let mut stages = match matches.opt_str("emit").map(|x| parse_stages(&x)) {
+        Some(Ok(stages)) => stages,
+        Some(Err(s)) => fail(&s),
+        None if matches.opt_present("ast") => vec![],
+        None => vec![Stage::Cfg]
+    };
+    if matches.opt_present("ast") && !stages.contains(&Stage::Ast) {
+        stages.push(Stage::Ast);
+    }
+
+    let output = Output {
+        stages: stages,
+        path: matches.opt_str("o"),
+        color: io::stdout().is_terminal(),
+        run: matches.opt_present("run"),
+        simulate: matches.opt_present("simulate")
+    };
+    ast::set_color(output.color);
+
+    let level = match matches.opt_str("O").map(|l| l.parse::<u8>()) {
+        Some(Ok(l)) if l <= 3 => l,
+        None => 0,
+        _ => fail(&"Optimization level must be 0, 1, 2 or 3")
+    };
 
     let filename = if !matches.free.is_empty() {
         matches.free[0].clone()
     } else {
-        print_usage(&program, opts);
-        return;
+        fail(&usage(&program, &opts));
     };
 
-    let mut input = File::open(filename).unwrap();
     let mut code = String::new();
-    input.read_to_string(&mut code).unwrap();
+    if let Err(e) = File::open(&filename).and_then(|mut input| input.read_to_string(&mut code)) {
+        fail(&format!("Cannot read {}: {}", filename, e));
+    }
+
+    let (tokens, comments) = match lexer::lex(&code) {
+        Ok(t) => t,
+        Err(d) => fail(&d.render(&filename, &code))
+    };
+
+    if output.emit(Stage::Tokens, &lexer::Tokens(&tokens)) {
+        return;
+    }
 
-    let ast = match parser::parse(code) {
+    let ast = match parser::parse_tokens(&tokens) {
         Ok(t) => t,
-        Err(s) => {
-            println!("{}", s);
-            return;
+        Err(d) => fail(&d.render(&filename, &code))
+    };
+
+    if output.emit(Stage::Ast, &ast::Commented(&ast, &comments)) {
+        return;
+    }
+
+    let (diagnostics, types) = typechecker::infer(&ast);
+    for d in diagnostics.iter() {
+        eprint!("{}", d.render(&filename, &code));
+    }
+
+    if diagnostic::has_errors(&diagnostics) {
+        process::exit(1);
+    }
+
+    if output.emit(Stage::Typed, &ast::Typed(&ast, &types)) {
+        return;
+    }
+
+    if let Some(keys) = matches.opt_str("run") {
+        let keys = match interp::parse_keys(&keys) {
+            Ok(k) => k,
+            Err(s) => fail(&s)
+        };
+
+        match interp::run(&ast, &keys) {
+            Ok(messages) => for message in messages {
+                println!("{}", message);
+            },
+            Err(s) => fail(&format!("Runtime error: {}", s))
         }
+        return;
+    }
+
+    for d in inline::copied(&ast).iter() {
+        eprint!("{}", d.render(&filename, &code));
+    }
+
+    let ast = match inline::inline(&ast) {
+        Ok(t) => t,
+        Err(d) => fail(&d.render(&filename, &code))
     };
 
-    if print_ast {
-        print!("{}", ast);
+    let diagnostics = optimize::check_text(&ast);
+    for d in diagnostics.iter() {
+        eprint!("{}", d.render(&filename, &code));
+    }
+
+    if diagnostic::has_errors(&diagnostics) {
+        process::exit(1);
+    }
+
+    if output.emit(Stage::Inlined, &ast) {
+        return;
     }
 
-    match typechecker::check(ast) {
-        Ok(_) => (),
-        Err(s) => {
-            println!("Typechecker error: {}", s);
-            return;
+    if output.wants(Stage::Ir) {
+        match lower::lower(&ast) {
+            Ok(program) => if output.emit(Stage::Ir, &program) {
+                return;
+            },
+            Err(s) => fail(&format!("Codegen error: {}", s))
         }
     }
+
+    let (program, passes) = match optimize::optimize(ast, level) {
+        Ok(result) => result,
+        Err(s) => fail(&format!("Codegen error: {}", s))
+    };
+
+    if let Err(s) = ir::validate(&program) {
+        fail(&format!("Invalid program: {}", s));
+    }
+
+    if output.emit(Stage::OptimizedIr, &program) {
+        return;
+    }
+
+    if let Some(keys) = matches.opt_str("simulate") {
+        let keys = match interp::parse_keys(&keys) {
+            Ok(k) => k,
+            Err(s) => fail(&s)
+        };
+
+        match vm::run(&codegen::emit(&program).to_string(), &keys) {
+            Ok(messages) => for message in messages {
+                println!("{}", message);
+            },
+            Err(s) => fail(&format!("Console error: {}", s))
+        }
+        return;
+    }
+
+    let mut config = codegen::emit(&program);
+    config.passes = passes;
+    output.emit(Stage::Cfg, &config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn output(stages : &str, path : Option<String>) -> Output {
+        Output { stages: parse_stages(stages).unwrap(), path: path, color: true, run: false, simulate: false }
+    }
+
+    #[test]
+    fn emit_stages() {
+        assert!(parse_stages("ast, cfg").is_ok());
+        assert!(parse_stages("asm").is_err());
+
+        // Nothing past the last stage asked for needs to run
+        let ir = output("ir", None);
+        assert!(!ir.last(Stage::Inlined));
+        assert!(ir.last(Stage::Ir));
+    }
+
+    // With several stages, -o names each file after its stage, and files are
+    // never coloured
+    #[test]
+    fn emit_to_files() {
+        let dir = env::temp_dir().join(format!("clockwerk-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.txt").to_string_lossy().into_owned();
+
+        let ast = parser::parse("main() { say(\"hi\"); }".to_string()).unwrap();
+        let several = output("ast,cfg", Some(path.clone()));
+        several.write(Stage::Ast, &ast).unwrap();
+        several.write(Stage::Cfg, &codegen::emit(&lower::lower(&ast).unwrap())).unwrap();
+
+        assert!(fs::read_to_string(dir.join("out.ast")).unwrap().starts_with("main() {\n    say(\"hi\");\n}\n"));
+        assert!(fs::read_to_string(dir.join("out.cfg")).unwrap().starts_with("// Generated by clockwerk\n"));
+
+        // A single stage goes to the path as given
+        output("cfg", Some(path.clone())).write(Stage::Cfg, &"alias x").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "alias x");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
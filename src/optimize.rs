@@ -0,0 +1,500 @@
+use std::collections::{HashMap, HashSet};
+
+use ast::*;
+use diagnostic::{Code, Diagnostic};
+use codegen;
+use codegen::PassReport;
+use ir::{Action, Program};
+use lower;
+use typechecker::unsafe_text;
+
+// Passes run in order, each enabled from the optimization level given here.
+// Passes over the AST run before lowering and passes over the IR after it.
+// Every pass is measured by the size of the config it leaves, since the game
+// caps how much a config may contain.
+
+static AST_PASSES : &'static [(&'static str, u8, fn(AST) -> AST)] = &[
+    ("constant folding", 1, fold_constants),
+    ("dead variables", 1, dead_variables)
+];
+
+static IR_PASSES : &'static [(&'static str, u8, fn(&mut Program))] = &[
+    ("alias deduplication", 2, deduplicate_aliases),
+    ("bind minimization", 3, minimize_binds)
+];
+
+// Evaluates operators whose operands are already constant
+fn constant(e : &Expr) -> Option<Value> {
+    match &e.kind {
+        &ExprKind::ConstBool(b) => Some(Value::Bool(b)),
+        &ExprKind::ConstInt(i) => Some(Value::Int(i)),
+        &ExprKind::ConstKey(k) => Some(Value::Key(k)),
+        &ExprKind::ConstColor(c) => Some(Value::Color(c)),
+        &ExprKind::ConstString(ref s) => Some(Value::Str(s.clone())),
+        &ExprKind::ConstList(ref l) => Some(Value::List(l.clone())),
+        _ => None
+    }
+}
+
+fn literal(v : Value) -> ExprKind {
+    match v {
+        Value::Bool(b) => ExprKind::ConstBool(b),
+        Value::Int(i) => ExprKind::ConstInt(i),
+        Value::Key(k) => ExprKind::ConstKey(k),
+        Value::Color(c) => ExprKind::ConstColor(c),
+        Value::Str(s) => ExprKind::ConstString(s),
+        Value::List(l) => ExprKind::ConstList(l)
+    }
+}
+
+// Concatenation is associative, so neighbouring strings are joined even when
+// the parse groups them apart, as in "a" + ("b" + x)
+fn concatenate(l : Expr, o : OpType, r : Expr) -> ExprKind {
+    match (l, o, r) {
+        (Expr { kind: ExprKind::ConstString(a), span: lo }, OpType::Plus, Expr { kind: ExprKind::Binop(rl, OpType::Plus, rr), span: hi }) => match rl.kind {
+            ExprKind::ConstString(b) => ExprKind::Binop(Box::new(Expr { kind: ExprKind::ConstString(a + &b), span: Span { lo: lo.lo, hi: rl.span.hi } }), o, rr),
+            kind => ExprKind::Binop(Box::new(Expr { kind: ExprKind::ConstString(a), span: lo }), o,
+                                    Box::new(Expr { kind: ExprKind::Binop(Box::new(Expr { kind: kind, span: rl.span }), OpType::Plus, rr), span: hi }))
+        },
+        (Expr { kind: ExprKind::Binop(ll, OpType::Plus, lr), span: lo }, OpType::Plus, Expr { kind: ExprKind::ConstString(b), span: hi }) => match lr.kind {
+            ExprKind::ConstString(a) => ExprKind::Binop(ll, o, Box::new(Expr { kind: ExprKind::ConstString(a + &b), span: Span { lo: lr.span.lo, hi: hi.hi } })),
+            kind => ExprKind::Binop(Box::new(Expr { kind: ExprKind::Binop(ll, OpType::Plus, Box::new(Expr { kind: kind, span: lr.span })), span: lo }), o,
+                                    Box::new(Expr { kind: ExprKind::ConstString(b), span: hi }))
+        },
+        (l, o, r) => ExprKind::Binop(Box::new(l), o, Box::new(r))
+    }
+}
+
+fn fold_expr(e : Expr) -> Expr {
+    let kind = match e.kind {
+        ExprKind::Binop(l, o, r) => {
+            let (l, r) = (fold_expr(*l), fold_expr(*r));
+            // Operations that fail, such as overflows, are left for the backend
+            // to report
+            match (constant(&l), constant(&r)) {
+                (Some(x), Some(y)) => match eval_binop(x, o, y) {
+                    Ok(v) => literal(v),
+                    Err(_) => ExprKind::Binop(Box::new(l), o, Box::new(r))
+                },
+                _ => concatenate(l, o, r)
+            }
+        },
+        ExprKind::Prefix(o, operand) => {
+            let operand = fold_expr(*operand);
+            match constant(&operand).map(|v| eval_prefix(o, v)) {
+                Some(Ok(v)) => literal(v),
+                _ => ExprKind::Prefix(o, Box::new(operand))
+            }
+        },
+        ExprKind::Elem(list, index) => ExprKind::Elem(Box::new(fold_expr(*list)), Box::new(fold_expr(*index))),
+        ExprKind::Call(func, args) => ExprKind::Call(func, args.into_iter().map(fold_expr).collect()),
+        kind => kind
+    };
+    Expr { kind: kind, span: e.span }
+}
+
+fn fold_statement(s : Statement) -> Statement {
+    let fold_box = |s : Box<Statement>| Box::new(fold_statement(*s));
+    let kind = match s.kind {
+        StatementKind::Mutable(t, name, e) => StatementKind::Mutable(t, name, fold_expr(e)),
+        StatementKind::Const(t, name, e) => StatementKind::Const(t, name, fold_expr(e)),
+        StatementKind::Assign(name, e) => StatementKind::Assign(name, fold_expr(e)),
+        StatementKind::Return(e) => StatementKind::Return(fold_expr(e)),
+        StatementKind::Expr(e) => StatementKind::Expr(fold_expr(e)),
+        StatementKind::Block(stmts) => StatementKind::Block(stmts.into_iter().map(fold_statement).collect()),
+        StatementKind::If(cond, then, otherwise) => StatementKind::If(fold_expr(cond), fold_box(then), otherwise.map(fold_box)),
+        StatementKind::Input(branches) => StatementKind::Input(branches.into_iter().map(|(key, arm)| (key, fold_statement(arm))).collect()),
+        StatementKind::Loop(body) => StatementKind::Loop(fold_box(body)),
+        StatementKind::While(cond, body) => StatementKind::While(fold_expr(cond), fold_box(body)),
+        StatementKind::Break => StatementKind::Break
+    };
+    Statement { kind: kind, span: s.span }
+}
+
+fn fold_constants(t : AST) -> AST {
+    AST(t.0.into_iter().map(|f| Function { body: fold_statement(f.body), ..f }).collect())
+}
+
+// Literals are checked by the typechecker, but joining them can still spell
+// out what no literal may contain
+fn joined_text(e : &Expr, diags : &mut Vec<Diagnostic>) {
+    match &e.kind {
+        &ExprKind::ConstString(ref text) => if let Some(part) = unsafe_text(text) {
+            diags.push(Diagnostic::error(Code::UnsafeText, e.span, format!("Joining these strings spells out {}, which would break the config they're chatted from", part)));
+        },
+        &ExprKind::Binop(ref l, _, ref r) | &ExprKind::Elem(ref l, ref r) => {
+            joined_text(l, diags);
+            joined_text(r, diags);
+        },
+        &ExprKind::Prefix(_, ref operand) => joined_text(operand, diags),
+        &ExprKind::Call(_, ref args) => for arg in args.iter() {
+            joined_text(arg, diags);
+        },
+        _ => ()
+    }
+}
+
+fn statement_text(s : &Statement, diags : &mut Vec<Diagnostic>) {
+    let mut check = |e : &Expr| joined_text(&fold_expr(e.clone()), diags);
+    match &s.kind {
+        &StatementKind::Mutable(_, _, ref e) | &StatementKind::Const(_, _, ref e) | &StatementKind::Assign(_, ref e) |
+        &StatementKind::Return(ref e) | &StatementKind::Expr(ref e) => check(e),
+        &StatementKind::Block(ref stmts) => for stmt in stmts.iter() {
+            statement_text(stmt, diags);
+        },
+        &StatementKind::If(ref cond, ref then, ref otherwise) => {
+            check(cond);
+            statement_text(then, diags);
+            if let &Some(ref otherwise) = otherwise {
+                statement_text(otherwise, diags);
+            }
+        },
+        &StatementKind::Input(ref branches) => for &(_, ref arm) in branches.iter() {
+            statement_text(arm, diags);
+        },
+        &StatementKind::Loop(ref body) => statement_text(body, diags),
+        &StatementKind::While(ref cond, ref body) => {
+            check(cond);
+            statement_text(body, diags);
+        },
+        &StatementKind::Break => ()
+    }
+}
+
+// Folds the strings of an inlined program to find any that can't be chatted.
+// This runs at every level, since the config has to work without folding too.
+pub fn check_text(t : &AST) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    for f in t.0.iter() {
+        statement_text(&f.body, &mut diags);
+    }
+    diags
+}
+
+// Whether an expression can be dropped without changing what the program does
+fn pure(e : &Expr) -> bool {
+    match &e.kind {
+        &ExprKind::Call(_, _) | &ExprKind::Unop(_, _) => false,
+        &ExprKind::Binop(ref l, _, ref r) | &ExprKind::Elem(ref l, ref r) => pure(l) && pure(r),
+        &ExprKind::Prefix(_, ref operand) => pure(operand),
+        _ => true
+    }
+}
+
+fn expr_reads(e : &Expr, reads : &mut HashSet<Ident>) {
+    match &e.kind {
+        &ExprKind::Var(ref name) | &ExprKind::Unop(ref name, _) => {
+            reads.insert(name.clone());
+        },
+        &ExprKind::Binop(ref l, _, ref r) | &ExprKind::Elem(ref l, ref r) => {
+            expr_reads(l, reads);
+            expr_reads(r, reads);
+        },
+        &ExprKind::Prefix(_, ref operand) => expr_reads(operand, reads),
+        &ExprKind::Call(_, ref args) => for arg in args.iter() {
+            expr_reads(arg, reads);
+        },
+        _ => ()
+    }
+}
+
+// Collects the variables whose value is used, and those written with
+// something that can't be dropped.  Names are collected regardless of scope,
+// so a shadowed variable keeps every variable of that name alive.
+fn statement_uses(s : &Statement, reads : &mut HashSet<Ident>, kept : &mut HashSet<Ident>) {
+    match &s.kind {
+        // Incrementing a variable on its own doesn't use its value
+        &StatementKind::Expr(Expr { kind: ExprKind::Unop(_, _), .. }) => (),
+        &StatementKind::Mutable(_, ref name, ref e) | &StatementKind::Const(_, ref name, ref e) | &StatementKind::Assign(ref name, ref e) => {
+            if !pure(e) {
+                kept.insert(name.clone());
+            }
+            expr_reads(e, reads);
+        },
+        &StatementKind::Return(ref e) | &StatementKind::Expr(ref e) => expr_reads(e, reads),
+        &StatementKind::Block(ref stmts) => for stmt in stmts.iter() {
+            statement_uses(stmt, reads, kept);
+        },
+        &StatementKind::If(ref cond, ref then, ref otherwise) => {
+            expr_reads(cond, reads);
+            statement_uses(then, reads, kept);
+            if let &Some(ref otherwise) = otherwise {
+                statement_uses(otherwise, reads, kept);
+            }
+        },
+        &StatementKind::Input(ref branches) => for &(_, ref arm) in branches.iter() {
+            statement_uses(arm, reads, kept);
+        },
+        &StatementKind::Loop(ref body) => statement_uses(body, reads, kept),
+        &StatementKind::While(ref cond, ref body) => {
+            expr_reads(cond, reads);
+            statement_uses(body, reads, kept);
+        },
+        &StatementKind::Break => ()
+    }
+}
+
+// Removes every declaration of and write to a dead variable, returning None
+// if nothing is left of the statement
+fn strip(s : Statement, dead : &HashSet<Ident>) -> Option<Statement> {
+    let span = s.span;
+    let strip_box = |s : Box<Statement>| Box::new(strip(*s, dead).unwrap_or(Statement { kind: StatementKind::Block(vec![]), span: span }));
+    let kind = match s.kind {
+        StatementKind::Mutable(_, ref name, _) | StatementKind::Const(_, ref name, _) | StatementKind::Assign(ref name, _) |
+        StatementKind::Expr(Expr { kind: ExprKind::Unop(ref name, _), .. }) if dead.contains(name) => return None,
+        StatementKind::Block(stmts) => StatementKind::Block(stmts.into_iter().filter_map(|stmt| strip(stmt, dead)).collect()),
+        StatementKind::If(cond, then, otherwise) => StatementKind::If(cond, strip_box(then), otherwise.map(&strip_box)),
+        StatementKind::Input(branches) => StatementKind::Input(branches.into_iter().map(|(key, arm)| {
+            (key, strip(arm, dead).unwrap_or(Statement { kind: StatementKind::Block(vec![]), span: span }))
+        }).collect()),
+        StatementKind::Loop(body) => StatementKind::Loop(strip_box(body)),
+        StatementKind::While(cond, body) => StatementKind::While(cond, strip_box(body)),
+        kind => kind
+    };
+    Some(Statement { kind: kind, span: span })
+}
+
+fn declared(s : &Statement, names : &mut HashSet<Ident>) {
+    match &s.kind {
+        &StatementKind::Mutable(_, ref name, _) | &StatementKind::Const(_, ref name, _) => {
+            names.insert(name.clone());
+        },
+        &StatementKind::Block(ref stmts) => for stmt in stmts.iter() {
+            declared(stmt, names);
+        },
+        &StatementKind::If(_, ref then, ref otherwise) => {
+            declared(then, names);
+            if let &Some(ref otherwise) = otherwise {
+                declared(otherwise, names);
+            }
+        },
+        &StatementKind::Input(ref branches) => for &(_, ref arm) in branches.iter() {
+            declared(arm, names);
+        },
+        &StatementKind::Loop(ref body) | &StatementKind::While(_, ref body) => declared(body, names),
+        _ => ()
+    }
+}
+
+// Variables that are never read only cost aliases.  Removing one can leave
+// the variables it was computed from unread, so this repeats until nothing
+// changes.
+fn dead_variables(t : AST) -> AST {
+    AST(t.0.into_iter().map(|f| {
+        let mut body = f.body;
+        loop {
+            let (mut names, mut reads, mut kept) = (HashSet::new(), HashSet::new(), HashSet::new());
+            declared(&body, &mut names);
+            statement_uses(&body, &mut reads, &mut kept);
+
+            let dead : HashSet<Ident> = names.into_iter().filter(|name| !reads.contains(name) && !kept.contains(name)).collect();
+            if dead.is_empty() {
+                break;
+            }
+            let span = body.span;
+            body = strip(body, &dead).unwrap_or(Statement { kind: StatementKind::Block(vec![]), span: span });
+        }
+        Function { body: body, ..f }
+    }).collect())
+}
+
+// Follows a chain of replaced states to the one that's kept
+fn resolve(name : &str, renames : &HashMap<String, String>) -> String {
+    let mut name = name.to_string();
+    let mut seen = HashSet::new();
+    while let Some(next) = renames.get(&name) {
+        if !seen.insert(name.clone()) {
+            break;
+        }
+        name = next.clone();
+    }
+    name
+}
+
+// States that only run another state are replaced by it, states with the
+// same actions are merged, and states nothing refers to are dropped
+fn deduplicate_aliases(p : &mut Program) {
+    // Slots are repointed while the program runs, so they're left alone
+    let dynamic = p.slots();
+
+    loop {
+        let mut renames = HashMap::new();
+        let mut bodies : HashMap<&[Action], String> = HashMap::new();
+        for state in p.states.iter().filter(|s| !dynamic.contains(&s.name)) {
+            if let [Action::Run(ref target)] = state.actions[..] {
+                if *target != state.name {
+                    renames.insert(state.name.clone(), target.clone());
+                    continue;
+                }
+            }
+
+            match bodies.get(&state.actions[..]) {
+                Some(first) => {
+                    renames.insert(state.name.clone(), first.clone());
+                },
+                None => {
+                    bodies.insert(&state.actions[..], state.name.clone());
+                }
+            }
+        }
+
+        if renames.is_empty() {
+            break;
+        }
+
+        p.states.retain(|s| !renames.contains_key(&s.name));
+        for action in p.states.iter_mut().flat_map(|s| s.actions.iter_mut()).chain(p.entry.iter_mut()) {
+            let target = action.target().map(|t| resolve(t, &renames));
+            if let Some(target) = target {
+                action.retarget(target);
+            }
+        }
+    }
+
+    // Sweep the states that can't be reached from the entry
+    let mut reachable = HashSet::new();
+    let mut pending : Vec<String> = p.entry.iter().filter_map(|a| a.target()).map(|t| t.to_string()).collect();
+    while let Some(name) = pending.pop() {
+        if !reachable.insert(name.clone()) {
+            continue;
+        }
+        if let Some(state) = p.state(&name) {
+            pending.extend(state.actions.iter().filter_map(|a| a.target()).map(|t| t.to_string()));
+        }
+    }
+    p.states.retain(|s| reachable.contains(&s.name));
+    for variable in p.variables.iter_mut() {
+        variable.states.retain(|s| reachable.contains(s));
+    }
+}
+
+// Keys a state is certain to leave bound once it has run.  Slots could point
+// anywhere, so only the states they run directly count.
+fn binds(name : &str, states : &HashMap<&str, &Vec<Action>>, memo : &mut HashMap<String, HashSet<KeyType>>) -> HashSet<KeyType> {
+    if let Some(keys) = memo.get(name) {
+        return keys.clone();
+    }
+
+    // Assume nothing while the state is being looked at, so cycles end
+    memo.insert(name.to_string(), HashSet::new());
+    let mut keys = HashSet::new();
+    if let Some(actions) = states.get(name) {
+        for action in actions.iter() {
+            match action {
+                &Action::Bind(key, _) => {
+                    keys.insert(key);
+                },
+                &Action::Run(ref callee) => keys.extend(binds(callee, states, memo)),
+                _ => ()
+            }
+        }
+    }
+    memo.insert(name.to_string(), keys.clone());
+    keys
+}
+
+// Keys can't be pressed while a state runs, so binding a key does nothing if
+// the key is certain to be bound again before the state ends.  This mostly
+// removes the restores of input arms that lead straight back into the same
+// input.
+fn minimize_binds(p : &mut Program) {
+    let dynamic = p.slots();
+    let minimized : Vec<Vec<Action>> = {
+        let states : HashMap<&str, &Vec<Action>> = p.states.iter()
+            .filter(|s| !dynamic.contains(&s.name))
+            .map(|s| (&*s.name, &s.actions))
+            .collect();
+        let mut memo = HashMap::new();
+
+        p.states.iter().map(|state| {
+            let mut later = HashSet::new();
+            let mut kept = vec![];
+            for action in state.actions.iter().rev() {
+                match action {
+                    &Action::Bind(key, _) if later.contains(&key) => continue,
+                    &Action::Bind(key, _) => {
+                        later.insert(key);
+                    },
+                    &Action::Run(ref callee) => later.extend(binds(callee, &states, &mut memo)),
+                    _ => ()
+                }
+                kept.push(action.clone());
+            }
+            kept.reverse();
+            kept
+        }).collect()
+    };
+
+    for (state, actions) in p.states.iter_mut().zip(minimized) {
+        state.actions = actions;
+    }
+}
+
+fn size(p : &Program) -> usize {
+    codegen::emit(p).size()
+}
+
+// Lowers a program, running every pass enabled at the given level.  Returns
+// how much each pass saved along with the result.
+pub fn optimize(t : AST, level : u8) -> Result<(Program, Vec<PassReport>), String> {
+    let mut t = t;
+    let mut passes = vec![];
+    let mut before = size(&try!(lower::lower(&t)));
+
+    for &(name, _, pass) in AST_PASSES.iter().filter(|p| level >= p.1) {
+        t = pass(t);
+        let after = size(&try!(lower::lower(&t)));
+        passes.push(PassReport { name: name, before: before, after: after });
+        before = after;
+    }
+
+    let mut program = try!(lower::lower(&t));
+    for &(name, _, pass) in IR_PASSES.iter().filter(|p| level >= p.1) {
+        pass(&mut program);
+        let after = size(&program);
+        passes.push(PassReport { name: name, before: before, after: after });
+        before = after;
+    }
+
+    return Ok((program, passes));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inline;
+    use parser;
+
+    // The code of every problem found in the inlined source
+    fn codes(source : &str) -> Vec<Code> {
+        check_text(&inline::inline(&parser::parse(source.to_string()).unwrap()).unwrap()).iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn joined_strings() {
+        assert_eq!(codes("main() { say(\"a/\" + \"/b\"); }"), vec![Code::UnsafeText]);
+        assert_eq!(codes("string g(string p) { return p + \"/b\"; } main() { say(g(\"a/\")); }"), vec![Code::UnsafeText]);
+        assert_eq!(codes("main() { say(\"a/\" + 1 + \"/b\"); }"), vec![]);
+    }
+
+    #[test]
+    fn pass_reports() {
+        let source = "main() { int x = 2 + 3; say(\"x is \" + x); }";
+        let ast = || inline::inline(&parser::parse(source.to_string()).unwrap()).unwrap();
+
+        let (_, passes) = optimize(ast(), 0).unwrap();
+        assert!(passes.is_empty());
+
+        // Every pass is reported in the order it ran, each starting where the last ended
+        let (program, passes) = optimize(ast(), 3).unwrap();
+        let names : Vec<_> = passes.iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["constant folding", "dead variables", "alias deduplication", "bind minimization"]);
+        assert!(passes.windows(2).all(|w| w[0].after == w[1].before));
+        assert_eq!(passes.last().unwrap().after, size(&program));
+
+        let mut config = codegen::emit(&program);
+        let comment = format!("// constant folding: {} -> {} bytes\n", passes[0].before, passes[0].after);
+        config.passes = passes;
+        assert!(config.to_string().contains(&comment));
+    }
+}
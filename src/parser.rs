@@ -1,306 +1,438 @@
 use nom::IResult;
-use nom::Err::Position;
-use nom::ErrorKind;
-use nom::{space, multispace, digit, alpha, alphanumeric};
-use std::str;
-use std::str::FromStr;
+use nom::{Err, ErrorKind};
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use ast::*;
+use diagnostic::{Code, Diagnostic};
+use lexer::{Lexeme, Token};
+use resolve;
 
-// TODO: parse comments
-// This should include a general whitespace solution also
+// Spans of the tokens being parsed, used to recover byte offsets from the
+// remaining input
+thread_local!(static SPANS: RefCell<Vec<Span>> = RefCell::new(vec![]));
 
-named!(boolean_literals<bool>,
-    alt!(
-        chain!(tag!("true"), || true)
-      | chain!(tag!("false"), || false)
+// The most tokens any rule has consumed.  Failures are reported at the next
+// token, since nom discards the positions of errors inside alternatives.
+thread_local!(static FURTHEST: Cell<usize> = Cell::new(0));
+
+fn index(input: &[Lexeme]) -> usize {
+    SPANS.with(|s| s.borrow().len()) - input.len()
+}
+
+fn advance(input: &[Lexeme]) -> &[Lexeme] {
+    let consumed = index(input) + 1;
+    FURTHEST.with(|f| f.set(cmp::max(f.get(), consumed)));
+    &input[1..]
+}
+
+// Why the last input head that didn't name a key doesn't, which says more
+// than a syntax error at whatever came after it
+thread_local!(static BAD_KEY: RefCell<Option<Diagnostic>> = RefCell::new(None));
+
+// Where the next token starts
+fn position(input: &[Lexeme]) -> IResult<&[Lexeme], usize> {
+    let lo = SPANS.with(|s| s.borrow()[index(input)].lo);
+    IResult::Done(input, lo)
+}
+
+// Where the last token consumed ends
+fn end(input: &[Lexeme]) -> IResult<&[Lexeme], usize> {
+    let hi = SPANS.with(|s| s.borrow()[index(input) - 1].hi);
+    IResult::Done(input, hi)
+}
+
+// Matches a single token, optionally producing a value from it
+macro_rules! token (
+    ($i:expr, $t:pat => $o:expr) => (
+        match $i.first() {
+            Some(&Lexeme { token: $t, .. }) => IResult::Done(advance($i), $o),
+            _ => IResult::Error(Err::Position(ErrorKind::Custom(1), $i))
+        }
+    );
+    ($i:expr, $t:pat) => (
+        token!($i, $t => ())
+    );
+);
+
+named!(boolean_literals<&[Lexeme], bool>, token!(Token::Bool(x) => x));
+
+named!(integer_literals<&[Lexeme], i32>, token!(Token::Int(x) => x));
+
+named!(signed_literals<&[Lexeme], i32>,
+    chain!(
+        negative: token!(Token::Minus)?
+      ~ x: integer_literals,
+      || if negative.is_some() { -x } else { x }
     )
 );
 
-// TODO: Negatives
-named!(integer_literals<u8>,
-   map_res!(
-       map_res!(
-           digit,
-           str::from_utf8
-       ),
-       FromStr::from_str
-   )
+named!(byte_literals<&[Lexeme], u8>,
+    map_opt!(integer_literals, |x : i32| if x <= u8::max_value() as i32 { Some(x as u8) } else { None })
 );
 
-// TODO: String literals cannot currently contain quotes
-named!(string_literals<String>,
-    chain!(
-        tag!("\"")
-      ~ text: map_res!(
-            take_until_and_consume!("\""),
-            str::from_utf8
+named!(string_literals<&[Lexeme], String>, token!(Token::Str(ref x) => x.clone()));
+
+// A key, or a modifier and the key pressed with it.  Key names are only read
+// as keys here, so elsewhere they're ordinary identifiers.
+named!(key_literals<&[Lexeme], KeyType>,
+    map_opt!(
+        chain!(
+            lo: position
+          ~ first: idents
+          ~ second: preceded!(token!(Token::Plus), idents)?
+          ~ hi: end,
+          || (first, second, Span { lo: lo, hi: hi })
         ),
-        || text.to_string()
+        |(first, second, span) : (Ident, Option<Ident>, Span)| {
+            let name = match second {
+                Some(ref key) => format!("{}+{}", first, key),
+                None => first.clone()
+            };
+            let key = KeyType::named(&name);
+            if key.is_none() {
+                let message = match second.as_ref().and_then(|x| KeyType::named(x)) {
+                    Some(ref key) if key.is_modifier() && KeyType::named(&first).map_or(false, |x| x.is_modifier()) =>
+                        format!("{} is not a key, since {} can't be held with another modifier", name, key),
+                    _ => format!("{} is not a key", name)
+                };
+                BAD_KEY.with(|k| *k.borrow_mut() = Some(Diagnostic::error(Code::SyntaxError, span, message)));
+            }
+            key
+        }
     )
 );
 
-// TODO: macros
-named!(key_literals<KeyType>,
-    alt!(
-        chain!(tag!("W"), || KeyType::W)
-      | chain!(tag!("A"), || KeyType::A)
-      | chain!(tag!("S"), || KeyType::S)
-      | chain!(tag!("D"), || KeyType::D)
+// color(r, g, b) for the colour closest to it.  Colours are also named, but
+// names are only read as colours once the parse knows they aren't variables.
+named!(color_literals<&[Lexeme], ColorType>,
+    chain!(
+        token!(Token::ColorType)
+      ~ token!(Token::LParen)
+      ~ r: byte_literals
+      ~ token!(Token::Comma)
+      ~ g: byte_literals
+      ~ token!(Token::Comma)
+      ~ b: byte_literals
+      ~ token!(Token::RParen),
+      || ColorType::nearest(r, g, b)
     )
 );
 
-// TODO: macros
-named!(color_literals<ColorType>,
-    alt!(
-        chain!(tag!("red"), || ColorType::Red)
-      | chain!(tag!("white"), || ColorType::White)
+named!(idents<&[Lexeme], Ident>, token!(Token::Ident(ref x) => x.clone()));
+
+named!(string_lists<&[Lexeme], Vec<String> >,
+    delimited!(
+        token!(Token::LBracket),
+        separated_list!(token!(Token::Comma), string_literals),
+        token!(Token::RBracket)
     )
 );
 
-named!(string_lists<Vec<String> >,
-    delimited!(
-        char!('['),
-        separated_list!(delimited!(opt!(multispace), char!(','), opt!(multispace)), string_literals),
-        char!(']')
+named!(additions<&[Lexeme], OpType>,
+    alt!(
+        token!(Token::Plus => OpType::Plus)
+      | token!(Token::Minus => OpType::Minus)
     )
 );
 
-named!(binops<OpType>,
+named!(comparisons<&[Lexeme], OpType>,
     alt!(
-        chain!(tag!("+"), || OpType::Plus)
-      | chain!(tag!("-"), || OpType::Minus)
-      | chain!(tag!("*"), || OpType::Times)
+        token!(Token::Equal => OpType::Equal)
+      | token!(Token::NotEqual => OpType::NotEqual)
+      | token!(Token::LessEqual => OpType::LessEqual)
+      | token!(Token::GreaterEqual => OpType::GreaterEqual)
+      | token!(Token::Less => OpType::Less)
+      | token!(Token::Greater => OpType::Greater)
     )
 );
 
-named!(unops<OpType>,
+named!(unops<&[Lexeme], OpType>,
     alt!(
-        chain!(tag!("++"), || OpType::UPlus)
-      | chain!(tag!("--"), || OpType::UMinus)
+        token!(Token::Increment => OpType::UPlus)
+      | token!(Token::Decrement => OpType::UMinus)
     )
 );
 
-named!(types<Type>,
+named!(types<&[Lexeme], Type>,
     alt!(
         chain!(
-            tag!("int")
+            token!(Token::IntType)
           ~ bounds: chain!(
-                char!('<')
-              ~ low: integer_literals
-              ~ char!(',')
-              ~ space?
-              ~ high: integer_literals
-              ~ char!('>'),
+                token!(Token::Less)
+              ~ low: signed_literals
+              ~ token!(Token::Comma)
+              ~ high: signed_literals
+              ~ token!(Token::Greater),
               || (low, high)
             )?,
             || match bounds {
                 Some((low, high)) => Type::Int(low, high),
-                None => Type::Int(0, u8::max_value())
+                None => Type::Int(0, INT_MAX)
             }
         )
-      | chain!(tag!("color"), || Type::Color)
-      | chain!(tag!("key"), || Type::Key)
-      | map!(delimited!(tag!("string<"), integer_literals, tag!(">")), |x : u8| Type::PrintableList(x))
-      | chain!(tag!("string"), || Type::Printable)
-      | chain!(tag!("bool"), || Type::Bool)
+      | token!(Token::ColorType => Type::Color)
+      | token!(Token::KeyType => Type::Key)
+      | chain!(
+            token!(Token::StringType)
+          ~ length: delimited!(token!(Token::Less), byte_literals, token!(Token::Greater))?,
+            || match length {
+                Some(x) => Type::PrintableList(x),
+                None => Type::Printable
+            }
+        )
+      | token!(Token::BoolType => Type::Bool)
     )
 );
 
-named!(idents<String>,
+named!(calls<&[Lexeme], ExprKind>,
     chain!(
-        first: map_res!(
-            alpha,
-            str::from_utf8
-        )
-      ~ remainder: map_res!(
-          alphanumeric,
-          str::from_utf8
-        )?,
-      || match remainder {
-          Some(r) => first.to_string() + r,
-          None => first.to_string()
-      }
+        func: idents
+      ~ token!(Token::LParen)
+      ~ args: separated_list!(token!(Token::Comma), exprs)
+      ~ token!(Token::RParen),
+      || ExprKind::Call(func, args)
     )
 );
 
-named!(calls<Expr>,
+named!(parens<&[Lexeme], Expr>,
+    delimited!(token!(Token::LParen), exprs, token!(Token::RParen))
+);
+
+named!(atoms<&[Lexeme], Expr>,
     chain!(
-        func: idents
-      ~ space?
-      ~ char!('(')
-      ~ multispace?
-      ~ args: separated_list!(
-            delimited!(opt!(multispace), char!(','), opt!(multispace)),
-            exprs
+        lo: position
+      ~ kind: alt!(
+            map!(boolean_literals, |x : bool| ExprKind::ConstBool(x))
+          | map!(signed_literals, |x : i32| ExprKind::ConstInt(x))
+          | map!(color_literals, |x : ColorType| ExprKind::ConstColor(x))
+          | map!(string_literals, |x : String| ExprKind::ConstString(x))
+          | map!(string_lists, |x : Vec<String>| ExprKind::ConstList(x))
+          | calls
+          | chain!(
+                l: idents
+              ~ o: unops,
+              || ExprKind::Unop(l, o)
+            )
+          | chain!(
+                token!(Token::Not)
+              ~ e: terms,
+              || ExprKind::Prefix(OpType::Not, Box::new(e))
+            )
+          | chain!(
+                token!(Token::Minus)
+              ~ e: terms,
+              || ExprKind::Prefix(OpType::Negate, Box::new(e))
+            )
+          | map!(idents, |x : Ident| ExprKind::Var(x))
+          | map!(parens, |x : Expr| x.kind)
         )
-      ~ char!(')')
-      ~ multispace?,
-      || Expr::Call(func, args)
+      ~ hi: end,
+      || Expr { kind: kind, span: Span { lo: lo, hi: hi } }
     )
 );
 
-named!(parens<Expr>,
-    delimited!(
-        chain!(char!('(') ~ multispace?, || ()),
-        exprs,
-        chain!(multispace? ~ char!(')'), || ())
+// Indexing applies to whatever it follows, so z[0][1] indexes twice
+named!(terms<&[Lexeme], Expr>,
+    chain!(
+        first: atoms
+      ~ indices: many0!(
+            chain!(
+                index: delimited!(token!(Token::LBracket), exprs, token!(Token::RBracket))
+              ~ hi: end,
+              || (index, hi)
+            )
+        ),
+      || indices.into_iter().fold(first, |list, (index, hi)| Expr {
+          span: Span { lo: list.span.lo, hi: hi },
+          kind: ExprKind::Elem(Box::new(list), Box::new(index))
+      })
+    )
+);
+
+fn binop(l : Expr, o : OpType, r : Expr) -> Expr {
+    Expr {
+        span: Span { lo: l.span.lo, hi: r.span.hi },
+        kind: ExprKind::Binop(Box::new(l), o, Box::new(r))
+    }
+}
+
+// Multiplication binds tightest, then addition and subtraction, then
+// comparisons, then && and finally ||.  Arithmetic groups from the left, so
+// 10 - 3 - 2 is 5.
+named!(products<&[Lexeme], Expr>,
+    chain!(
+        first: terms
+      ~ rest: many0!(preceded!(token!(Token::Times), terms)),
+      || rest.into_iter().fold(first, |l, r| binop(l, OpType::Times, r))
+    )
+);
+
+named!(sums<&[Lexeme], Expr>,
+    chain!(
+        first: products
+      ~ rest: many0!(pair!(additions, products)),
+      || rest.into_iter().fold(first, |l, (o, r)| binop(l, o, r))
     )
 );
 
-named!(terms<Expr>,
+// Comparisons don't chain, since a bool can't be compared with an int
+named!(relations<&[Lexeme], Expr>,
     alt!(
-        map!(boolean_literals, |x : bool| Expr::ConstBool(x))
-      | map!(integer_literals, |x : u8| Expr::ConstInt(x))
-      | map!(key_literals, |x : KeyType| Expr::ConstKey(x))
-      | map!(color_literals, |x : ColorType| Expr::ConstColor(x))
-      | map!(string_literals, |x : String| Expr::ConstString(x))
-      | map!(string_lists, |x : Vec<String>| Expr::ConstList(x))
-      | calls
-      | map!(idents, |x : Ident| Expr::Var(x))
-      | chain!(
-            l: idents
-          ~ space?
-          ~ o: unops,
-          || Expr::Unop(l, o)
-        )
-      | parens
+        chain!(l: sums ~ o: comparisons ~ r: sums, || binop(l, o, r))
+      | sums
     )
 );
 
-named!(exprs<Expr>,
-    delimited!(
-        opt!(multispace),
-        alt!(
-            chain!(
-                l: terms
-              ~ multispace?
-              ~ o: binops
-              ~ multispace?
-              ~ r: exprs,
-              || Expr::Binop(Box::new(l), o, Box::new(r))
-            )
-          | chain!(
-                list: terms
-              ~ multispace?
-              ~ index: delimited!(char!('['), exprs, char!(']')),
-              || Expr::Elem(Box::new(list), Box::new(index))
-            )
-          | terms
-        ),
-        opt!(multispace)
+named!(conjunctions<&[Lexeme], Expr>,
+    alt!(
+        chain!(l: relations ~ token!(Token::And) ~ r: conjunctions, || binop(l, OpType::And, r))
+      | relations
+    )
+);
+
+named!(exprs<&[Lexeme], Expr>,
+    alt!(
+        chain!(l: conjunctions ~ token!(Token::Or) ~ r: exprs, || binop(l, OpType::Or, r))
+      | conjunctions
     )
 );
 
-named!(declarations<Statement>,
+named!(declarations<&[Lexeme], StatementKind>,
     chain!(
-        mutable: opt!(terminated!(tag!("mut"), space))
+        mutable: token!(Token::Mut)?
       ~ typ: types
-      ~ space
       ~ name: idents
-      ~ space?
-      ~ char!('=')
-      ~ space?
+      ~ token!(Token::Assign)
       ~ value: exprs,
       || match mutable {
-          Some(_) => Statement::Mutable(typ, name, value),
-          None => Statement::Const(typ, name, value)
+          Some(_) => StatementKind::Mutable(typ, name, value),
+          None => StatementKind::Const(typ, name, value)
       }
     )
 );
 
-named!(input_cases<(KeyType, Statement)>,
+named!(input_cases<&[Lexeme], (KeyType, Statement)>,
     chain!(
-        multispace?
-      ~ case: key_literals
-      ~ space?
-      ~ tag!("=>")
-      ~ space?
-      ~ control: statements
-      ~ multispace?,
+        case: key_literals
+      ~ token!(Token::Arrow)
+      ~ control: statements,
       || (case, control)
     )
 );
 
-named!(statements<Statement>,
-    preceded!(
-        opt!(multispace),
-        alt!(
+named!(statements<&[Lexeme], Statement>,
+    chain!(
+        lo: position
+      ~ kind: alt!(
             chain!( // Blocks
-                tag!("{")
-              ~ multispace?
+                token!(Token::LBrace)
               ~ statements: many0!(statements)
-              ~ multispace?
-              ~ tag!("}"),
-              || Statement::Block(statements)
+              ~ token!(Token::RBrace),
+              || StatementKind::Block(statements)
             )
           | chain!(
-                tag!("input")
-              ~ multispace?
-              ~ tag!("{")
+                token!(Token::If)
+              ~ cond: parens
+              ~ then: statements
+              ~ otherwise: preceded!(token!(Token::Else), statements)?,
+              || StatementKind::If(cond, Box::new(then), otherwise.map(Box::new))
+            )
+          | chain!(
+                token!(Token::Loop)
+              ~ peek!(token!(Token::LBrace))
+              ~ body: statements,
+              || StatementKind::Loop(Box::new(body))
+            )
+          | chain!(
+                token!(Token::While)
+              ~ cond: parens
+              ~ body: statements,
+              || StatementKind::While(cond, Box::new(body))
+            )
+          | chain!(
+                token!(Token::Input)
+              ~ token!(Token::LBrace)
               ~ cases: many1!(input_cases)
-              ~ tag!("}"),
-              || Statement::Input(cases)
+              ~ token!(Token::RBrace),
+              || StatementKind::Input(cases)
             )
           | terminated!(
                 alt!(
-                    chain!(tag!("break"), || Statement::Break)
+                    token!(Token::Break => StatementKind::Break)
                   | declarations
                   | chain!(
-                        tag!("return")
-                      ~ multispace?
+                        token!(Token::Return)
                       ~ expr: exprs,
-                      || Statement::Return(expr)
+                      || StatementKind::Return(expr)
                     )
                   | chain!(
                         l: idents
-                      ~ delimited!(opt!(space), char!('='), opt!(space))
+                      ~ token!(Token::Assign)
                       ~ r: exprs,
-                      || Statement::Assign(l, r)
+                      || StatementKind::Assign(l, r)
                     )
-                  | map!(exprs, |x : Expr| Statement::Expr(x))
+                  | map!(exprs, |x : Expr| StatementKind::Expr(x))
                 ),
-                preceded!(opt!(space), tag!(";"))
+                token!(Token::Semicolon)
             )
         )
+      ~ hi: end,
+      || Statement { kind: kind, span: Span { lo: lo, hi: hi } }
     )
 );
 
-named!(arguments<(Type, Ident)>,
-    delimited!(
-        opt!(multispace),
-        separated_pair!(types, space, idents),
-        opt!(multispace)
-    )
+named!(arguments<&[Lexeme], (Type, Ident)>,
+    chain!(typ: types ~ name: idents, || (typ, name))
 );
 
-named!(functions<Function>,
+named!(functions<&[Lexeme], Function>,
     chain!(
-        ret: terminated!(types, space)?
+        lo: position
+      ~ ret: types?
       ~ name: idents
-      ~ space?
-      ~ args: delimited!(char!('('), separated_list!(char!(','), arguments), char!(')'))
+      ~ args: delimited!(token!(Token::LParen), separated_list!(token!(Token::Comma), arguments), token!(Token::RParen))
+      ~ hi: end
       ~ body: error!(ErrorKind::Custom(0), statements),
-      || Function { ret: ret, name: name, args: args, body: body}
+      || Function { ret: ret, name: name, args: args, body: body, span: Span { lo: lo, hi: hi } }
     )
 );
 
-// TODO: silently ignores failure when parsing functions - should look for
-// non-spaces and fail if they've been seen
-named!(files<AST>,
+named!(files<&[Lexeme], AST>,
     map!(
-        many1!(delimited!(opt!(multispace), functions, opt!(multispace))),
+        terminated!(many0!(functions), token!(Token::Eof)),
         |x : Vec<Function>| AST(x)
     )
 );
 
-pub fn parse(source: String) -> Result<AST, String> {
-    match files(source.as_bytes()) {
-        IResult::Done(_, t) => Ok(t),
-        IResult::Error(e) =>
-            match e {
-                Position(code, bytes) => Err(format!("Parse error at {:?}: {:?}", code, str::from_utf8(bytes).unwrap())),
-                _ => Err(format!("Unformatted error :("))
-            },
-        IResult::Incomplete(n) => Err(format!("Parse incomplete: needs {:?}", n))
-    }
+#[cfg(test)]
+pub fn parse(source: String) -> Result<AST, Diagnostic> {
+    let (tokens, _) = try!(::lexer::lex(&source));
+    parse_tokens(&tokens)
+}
+
+// Parses the output of the lexer, which ends with Eof
+pub fn parse_tokens(tokens: &[Lexeme]) -> Result<AST, Diagnostic> {
+    SPANS.with(|s| *s.borrow_mut() = tokens.iter().map(|t| t.span).collect());
+    FURTHEST.with(|f| f.set(0));
+    BAD_KEY.with(|k| *k.borrow_mut() = None);
+
+    let ast = match files(tokens) {
+        IResult::Done(_, t) => t,
+        IResult::Incomplete(n) => {
+            let end = tokens[tokens.len() - 1].span;
+            return Err(Diagnostic::error(Code::SyntaxError, end, format!("Parse incomplete: needs {:?}", n)));
+        },
+        IResult::Error(_) => {
+            if let Some(d) = BAD_KEY.with(|k| k.borrow_mut().take()) {
+                return Err(d);
+            }
+
+            // Report the error at the token after the furthest point that parsed
+            let furthest = FURTHEST.with(|f| f.get());
+            let span = tokens[cmp::min(furthest, tokens.len() - 1)].span;
+            return Err(Diagnostic::error(Code::SyntaxError, span, "Syntax error".to_string()));
+        }
+    };
+
+    return Ok(resolve::resolve(ast));
 }
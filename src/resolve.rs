@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use ast::*;
+use scope::Scopes;
+
+// Key and colour names aren't reserved, so they're free to name variables.
+// Where a name isn't bound to a variable it's read as the colour it names,
+// or as the key it names where a key is expected, after the parse, since
+// only then is it known which names are variables.  Elsewhere a key name is
+// left a variable, so a forgotten declaration is still reported as one.
+
+type Bound = Scopes<Type>;
+
+// The return and argument types of every function
+type Signatures = HashMap<Ident, (Option<Type>, Vec<Type>)>;
+
+// The key a name stands for, unless a variable has that name
+fn key(name : &str, bound : &Bound) -> Option<KeyType> {
+    if bound.get(name).is_some() {
+        return None;
+    }
+    KeyType::named(name)
+}
+
+fn color(name : &str, bound : &Bound) -> Option<ColorType> {
+    if bound.get(name).is_some() {
+        return None;
+    }
+    ColorType::named(name)
+}
+
+// A modifier and the key pressed with it, as in ALT+Q
+fn chord(l : &Expr, r : &Expr, bound : &Bound) -> Option<KeyType> {
+    match (&l.kind, &r.kind) {
+        (&ExprKind::Var(ref modifier), &ExprKind::Var(ref name)) => match (key(modifier, bound), key(name, bound)) {
+            (Some(modifier), Some(key)) => modifier.with(key),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+// Whether an expression is known to be a key without resolving any names,
+// which is what makes the other side of a comparison a key position
+fn is_key(e : &Expr, bound : &Bound, functions : &Signatures) -> bool {
+    match &e.kind {
+        &ExprKind::ConstKey(_) => true,
+        &ExprKind::Var(ref name) => bound.get(name) == Some(&Type::Key),
+        &ExprKind::Call(ref func, _) => functions.get(func).map_or(false, |f| f.0 == Some(Type::Key)),
+        _ => false
+    }
+}
+
+// Resolves the names in e, reading key names as keys if a key is expected
+fn resolve_expr(e : Expr, expected : Option<Type>, bound : &Bound, functions : &Signatures) -> Expr {
+    let keyed = expected == Some(Type::Key);
+    let kind = match e.kind {
+        ExprKind::Var(name) => match (key(&name, bound), color(&name, bound)) {
+            (Some(k), _) if keyed => ExprKind::ConstKey(k),
+            (_, Some(c)) => ExprKind::ConstColor(c),
+            _ => ExprKind::Var(name)
+        },
+        ExprKind::Binop(l, o, r) => match (o, chord(&l, &r, bound)) {
+            (OpType::Plus, Some(k)) if keyed => ExprKind::ConstKey(k),
+            (OpType::Equal, _) | (OpType::NotEqual, _) => {
+                let left = if is_key(&r, bound, functions) { Some(Type::Key) } else { None };
+                let right = if is_key(&l, bound, functions) { Some(Type::Key) } else { None };
+                ExprKind::Binop(Box::new(resolve_expr(*l, left, bound, functions)), o, Box::new(resolve_expr(*r, right, bound, functions)))
+            },
+            _ => ExprKind::Binop(Box::new(resolve_expr(*l, None, bound, functions)), o, Box::new(resolve_expr(*r, None, bound, functions)))
+        },
+        ExprKind::Prefix(o, operand) => ExprKind::Prefix(o, Box::new(resolve_expr(*operand, None, bound, functions))),
+        ExprKind::Elem(list, index) => ExprKind::Elem(Box::new(resolve_expr(*list, None, bound, functions)), Box::new(resolve_expr(*index, None, bound, functions))),
+        ExprKind::Call(func, args) => {
+            let params = functions.get(&func).map(|f| f.1.clone()).unwrap_or(vec![]);
+            let args = args.into_iter().enumerate().map(|(i, x)| resolve_expr(x, params.get(i).cloned(), bound, functions)).collect();
+            ExprKind::Call(func, args)
+        },
+        kind => kind
+    };
+    Expr { kind: kind, span: e.span }
+}
+
+// Scopes follow the typechecker's: blocks, branches, arms and loop bodies
+// each get their own
+fn resolve_scoped(s : Statement, ret : Option<Type>, bound : &mut Bound, functions : &Signatures) -> Statement {
+    bound.push();
+    let s = resolve_statement(s, ret, bound, functions);
+    bound.pop();
+    s
+}
+
+fn resolve_statement(s : Statement, ret : Option<Type>, bound : &mut Bound, functions : &Signatures) -> Statement {
+    let kind = match s.kind {
+        StatementKind::Mutable(t, name, e) => {
+            let e = resolve_expr(e, Some(t), bound, functions);
+            bound.declare(name.clone(), t);
+            StatementKind::Mutable(t, name, e)
+        },
+        StatementKind::Const(t, name, e) => {
+            let e = resolve_expr(e, Some(t), bound, functions);
+            bound.declare(name.clone(), t);
+            StatementKind::Const(t, name, e)
+        },
+        StatementKind::Assign(name, e) => {
+            let t = bound.get(&name).cloned();
+            StatementKind::Assign(name, resolve_expr(e, t, bound, functions))
+        },
+        StatementKind::Return(e) => StatementKind::Return(resolve_expr(e, ret, bound, functions)),
+        StatementKind::Expr(e) => StatementKind::Expr(resolve_expr(e, None, bound, functions)),
+        StatementKind::Block(stmts) => {
+            bound.push();
+            let stmts = stmts.into_iter().map(|x| resolve_statement(x, ret, bound, functions)).collect();
+            bound.pop();
+            StatementKind::Block(stmts)
+        },
+        StatementKind::If(cond, then, otherwise) => {
+            let cond = resolve_expr(cond, None, bound, functions);
+            let then = resolve_scoped(*then, ret, bound, functions);
+            StatementKind::If(cond, Box::new(then), otherwise.map(|x| Box::new(resolve_scoped(*x, ret, bound, functions))))
+        },
+        StatementKind::Input(branches) =>
+            StatementKind::Input(branches.into_iter().map(|(key, arm)| (key, resolve_scoped(arm, ret, bound, functions))).collect()),
+        StatementKind::Loop(body) => StatementKind::Loop(Box::new(resolve_scoped(*body, ret, bound, functions))),
+        StatementKind::While(cond, body) => {
+            let cond = resolve_expr(cond, None, bound, functions);
+            StatementKind::While(cond, Box::new(resolve_scoped(*body, ret, bound, functions)))
+        },
+        StatementKind::Break => StatementKind::Break
+    };
+    Statement { kind: kind, span: s.span }
+}
+
+// Replaces every name that isn't a variable but names a colour, or a key
+// where one is expected, with the constant it names
+pub fn resolve(t : AST) -> AST {
+    let functions : Signatures = t.0.iter().map(|f| (f.name.clone(), (f.ret, f.args.iter().map(|&(t, _)| t).collect()))).collect();
+
+    AST(t.0.into_iter().map(|f| {
+        let mut bound = Bound::new();
+        for &(t, ref arg) in f.args.iter() {
+            bound.declare(arg.clone(), t);
+        }
+        Function { body: resolve_statement(f.body, f.ret, &mut bound, &functions), ..f }
+    }).collect())
+}
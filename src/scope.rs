@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use ast::Ident;
+
+// Lexically scoped variables.  Each block gets its own scope, so names
+// declared inside it are dropped at its end and may shadow outer ones.
+#[derive(Clone)]
+pub struct Scopes<T> {
+    frames: Vec<HashMap<Ident, T>>
+}
+
+impl<T> Scopes<T> {
+    pub fn new() -> Scopes<T> {
+        Scopes { frames: vec![HashMap::new()] }
+    }
+
+    pub fn push(&mut self) {
+        self.frames.push(HashMap::new());
+    }
+
+    pub fn pop(&mut self) {
+        self.frames.pop();
+    }
+
+    // The innermost variable with this name
+    pub fn get(&self, name : &str) -> Option<&T> {
+        self.frames.iter().rev().filter_map(|frame| frame.get(name)).next()
+    }
+
+    // The variable with this name if it was declared in the current scope
+    pub fn get_local(&self, name : &str) -> Option<&T> {
+        self.frames.last().and_then(|frame| frame.get(name))
+    }
+
+    // Declares a variable in the current scope, shadowing any outer ones
+    pub fn declare(&mut self, name : Ident, value : T) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.insert(name, value);
+        }
+    }
+
+    // Updates the innermost variable with this name, returning whether there
+    // was one
+    pub fn set(&mut self, name : &str, value : T) -> bool {
+        match self.frames.iter_mut().rev().filter_map(|frame| frame.get_mut(name)).next() {
+            Some(v) => {
+                *v = value;
+                true
+            },
+            None => false
+        }
+    }
+}
@@ -1,11 +1,19 @@
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::cmp;
 
 use ast::*;
+use codegen::default_bind;
+use lexer;
+use diagnostic::{Code, Diagnostic, Note};
+use lower::restore_alias;
+use scope::Scopes;
 
 type FunctionContext = HashMap<Ident, (Type, Vec<Type>)>;
-type VariableContext = HashMap<Ident, (Type, bool)>;
+type VariableContext = Scopes<(Type, bool, Span)>;
+
+// Errors are collected rather than returned, so Err only signals that the
+// result is unknown and dependent checks should be skipped
+type Checked<T> = Result<T, ()>;
 
 fn subtype(t1 : &Type, t2 : &Type) -> bool {
     if t1 == t2 {
@@ -18,213 +26,532 @@ fn subtype(t1 : &Type, t2 : &Type) -> bool {
     }
 }
 
-fn check_expr(e : &Expr, func_table : &FunctionContext, context : &VariableContext) -> Result<Type, String> {
-    match e {
-        &Expr::ConstBool(_) => Ok(Type::Bool),
-        &Expr::ConstInt(x) => if x == u8::max_value() {
-            Err(format!("Integer constants must be less than {}", u8::max_value()))
+// Range of the values an arithmetic operator can produce
+fn interval(o : OpType, (l1, h1) : (i32, i32), (l2, h2) : (i32, i32)) -> (i32, i32) {
+    match o {
+        OpType::Plus => (l1 + l2, h1 + h2),
+        OpType::Minus => (l1 - h2, h1 - l2),
+        _ => {
+            // With signs, any pair of bounds can give either extreme
+            let products = [l1 * l2, l1 * h2, h1 * l2, h1 * h2];
+            (*products.iter().min().unwrap(), *products.iter().max().unwrap())
+        }
+    }
+}
+
+// Chat is written unquoted into the config, where a quote would end the
+// alias it's in, a semicolon would start another command and // would
+// comment out the rest of the line
+pub fn unsafe_text(text : &str) -> Option<&'static str> {
+    if text.contains('"') {
+        Some("\"")
+    } else if text.contains(';') {
+        Some(";")
+    } else if text.contains("//") {
+        Some("//")
+    } else {
+        None
+    }
+}
+
+// The type of every expression that checked, by where it was parsed from
+type Inferred = HashMap<Span, Type>;
+
+fn check_expr(e : &Expr, func_table : &FunctionContext, context : &VariableContext, types : &mut Inferred, diags : &mut Vec<Diagnostic>) -> Checked<Type> {
+    let t = infer_expr(e, func_table, context, types, diags);
+    if let Ok(t) = t {
+        types.insert(e.span, t);
+    }
+    t
+}
+
+fn infer_expr(e : &Expr, func_table : &FunctionContext, context : &VariableContext, types : &mut Inferred, diags : &mut Vec<Diagnostic>) -> Checked<Type> {
+    let mut error = |code : Code, message : String| {
+        diags.push(Diagnostic::error(code, e.span, message));
+        Err(())
+    };
+
+    match &e.kind {
+        &ExprKind::ConstBool(_) => Ok(Type::Bool),
+        &ExprKind::ConstInt(x) => if x < INT_MIN || x > INT_MAX {
+            error(Code::IntegerOutOfRange, format!("Integer constant {} is outside the integers, which range from {} to {}", x, INT_MIN, INT_MAX))
         } else {
-            Ok(Type::Int(0, x + 1))
+            Ok(Type::Int(x, x))
+        },
+        &ExprKind::ConstKey(_) => Ok(Type::Key),
+        &ExprKind::ConstColor(_) => Ok(Type::Color),
+        &ExprKind::ConstString(ref text) => match unsafe_text(text) {
+            Some(part) => error(Code::UnsafeText, format!("Strings cannot contain {}, which would break the config they're chatted from", part)),
+            None => Ok(Type::Printable)
         },
-        &Expr::ConstKey(_) => Ok(Type::Key),
-        &Expr::ConstColor(_) => Ok(Type::Color),
-        &Expr::ConstString(_) => Ok(Type::Printable),
-        &Expr::ConstList(ref elems) => {
+        &ExprKind::ConstList(ref elems) => {
+            let unsafe_elem = elems.iter().filter_map(|x| unsafe_text(x).map(|part| (x, part))).next();
             if elems.len() > (u8::max_value() as usize) {
-                Err(format!("Lists must contain less than {} elements", u8::max_value()))
+                error(Code::IndexOutOfBounds, format!("Lists must contain less than {} elements", u8::max_value()))
+            } else if let Some((elem, part)) = unsafe_elem {
+                diags.push(Diagnostic::error(Code::UnsafeText, e.span, format!("Strings cannot contain {}, which would break the config they're chatted from", part))
+                           .with_note(None, format!("the element {} contains it", lexer::quote(elem))));
+                Err(())
             } else {
                 Ok(Type::PrintableList(elems.len() as u8))
             }
         },
-        &Expr::Var(ref name) => {
+        &ExprKind::Var(ref name) => {
             match context.get(&*name) {
-                Some(&(ref t, _)) => Ok(*t),
-                None => Err(format!("Variable {} is referenced without being defined", name))
+                Some(&(ref t, _, _)) => Ok(*t),
+                None => error(Code::UndefinedVariable, format!("Variable {} is referenced without being defined", name))
             }
         },
-        &Expr::Binop(ref l, ref o, ref r) => {
-            let t1 = try!(check_expr(l, func_table, context));
-            let t2 = try!(check_expr(r, func_table, context));
+        &ExprKind::Binop(ref l, ref o, ref r) => {
+            let t1 = check_expr(l, func_table, context, types, diags);
+            let t2 = check_expr(r, func_table, context, types, diags);
+            let (t1, t2) = match (t1, t2) {
+                (Ok(t1), Ok(t2)) => (t1, t2),
+                _ => return Err(())
+            };
+
+            let mut error = |code : Code, message : String| {
+                diags.push(Diagnostic::error(code, e.span, message));
+                Err(())
+            };
 
             match (t1, *o, t2) {
-                (_, OpType::UPlus, _) => Err(format!("Unary operator {} may not be used in a binary expression", o)),
-                (_, OpType::UMinus, _) => Err(format!("Unary operator {} may not be used in a binary expression", o)),
-                (Type::Int(l1,h1), _, Type::Int(l2, h2)) => Ok(Type::Int(cmp::min(l1, l2), cmp::max(h1, h2))),
+                (_, OpType::UPlus, _) => error(Code::InvalidOperator, format!("Unary operator {} may not be used in a binary expression", o)),
+                (_, OpType::UMinus, _) => error(Code::InvalidOperator, format!("Unary operator {} may not be used in a binary expression", o)),
+                (_, OpType::Not, _) => error(Code::InvalidOperator, format!("Unary operator {} may not be used in a binary expression", o)),
+                (Type::Int(_, _), OpType::Equal, Type::Int(_, _)) | (Type::Int(_, _), OpType::NotEqual, Type::Int(_, _)) => Ok(Type::Bool),
+                (Type::Key, OpType::Equal, Type::Key) | (Type::Key, OpType::NotEqual, Type::Key) => Ok(Type::Bool),
+                (Type::Color, OpType::Equal, Type::Color) | (Type::Color, OpType::NotEqual, Type::Color) => Ok(Type::Bool),
+                (Type::Int(_, _), OpType::Less, Type::Int(_, _)) | (Type::Int(_, _), OpType::LessEqual, Type::Int(_, _)) => Ok(Type::Bool),
+                (Type::Int(_, _), OpType::Greater, Type::Int(_, _)) | (Type::Int(_, _), OpType::GreaterEqual, Type::Int(_, _)) => Ok(Type::Bool),
+                (Type::Bool, OpType::And, Type::Bool) | (Type::Bool, OpType::Or, Type::Bool) => Ok(Type::Bool),
+                (Type::Int(l1, h1), OpType::Plus, Type::Int(l2, h2)) |
+                (Type::Int(l1, h1), OpType::Minus, Type::Int(l2, h2)) |
+                (Type::Int(l1, h1), OpType::Times, Type::Int(l2, h2)) => {
+                    let (low, high) = interval(*o, (l1, h1), (l2, h2));
+                    let range = format!("the result ranges from {} to {}", low, high);
+                    if low > INT_MAX {
+                        diags.push(Diagnostic::error(Code::IntegerOverflow, e.span, format!("{} {} {} always overflows", t1, o, t2)).with_note(None, range));
+                        return Err(());
+                    } else if high < INT_MIN {
+                        diags.push(Diagnostic::error(Code::IntegerOverflow, e.span, format!("{} {} {} always underflows", t1, o, t2)).with_note(None, range));
+                        return Err(());
+                    } else if high > INT_MAX || low < INT_MIN {
+                        // Runs that stay in range are still well defined
+                        diags.push(Diagnostic::warning(Code::IntegerOverflow, e.span, format!("{} {} {} may leave the range of integers", t1, o, t2))
+                                   .with_note(None, range));
+                    }
+                    Ok(Type::Int(cmp::max(low, INT_MIN), cmp::min(high, INT_MAX)))
+                },
                 (Type::Printable, OpType::Plus, Type::Printable) => Ok(Type::Printable),
                 (Type::Printable, OpType::Plus, Type::Int(_, _)) => Ok(Type::Printable),
-                (Type::Printable, OpType::Times, Type::Int(_, _)) => Ok(Type::Printable),
+                (Type::Printable, OpType::Times, Type::Int(low, _)) if low >= 0 => Ok(Type::Printable),
                 (Type::Printable, OpType::Plus, Type::Color) => Ok(Type::Printable),
                 (Type::Color, OpType::Plus, Type::Printable) => Ok(Type::Printable),
-                _ => Err(format!("Operator {} does not operate on ({} x {})", o, t1, t2))
+                _ => error(Code::InvalidOperator, format!("Operator {} does not operate on ({} x {})", o, t1, t2))
             }
         },
-        &Expr::Unop(ref name, ref o) => {
+        &ExprKind::Unop(ref name, ref o) => {
             match context.get(&*name) {
-                Some(&(Type::Int(l, h), true)) => Ok(Type::Int(l, h)),
-                Some(&(_, false)) => Err(format!("Cannot modify immutable variable {}", name)),
-                _ => Err(format!("Unary operator {} can only be used on integers", o))
+                Some(&(Type::Int(l, h), true, _)) => Ok(Type::Int(l, h)),
+                Some(&(_, false, span)) => {
+                    diags.push(Diagnostic::error(Code::ImmutableAssignment, e.span, format!("Cannot modify immutable variable {}", name))
+                               .with_note(Some(span), format!("{} is declared here without mut", name)));
+                    Err(())
+                },
+                Some(_) => error(Code::InvalidOperator, format!("Unary operator {} can only be used on integers", o)),
+                None => error(Code::UndefinedVariable, format!("Variable {} is referenced without being defined", name))
             }
         },
-        &Expr::Call(ref func, ref args) => {
+        &ExprKind::Prefix(ref o, ref operand) => {
+            let t = try!(check_expr(operand, func_table, context, types, diags));
+            match (*o, t) {
+                (OpType::Not, Type::Bool) => Ok(Type::Bool),
+                (OpType::Negate, Type::Int(low, high)) => Ok(Type::Int(-high, -low)),
+                _ => {
+                    diags.push(Diagnostic::error(Code::InvalidOperator, e.span, format!("Operator {} does not operate on {}", o, t)));
+                    Err(())
+                }
+            }
+        },
+        &ExprKind::Call(ref func, ref args) => {
+            // Make sure all arguments typecheck, even if the call itself doesn't
+            let arg_types : Vec<_> = args.iter().map(|e| check_expr(e, func_table, context, types, diags)).collect();
+
+            let mut error = |code : Code, message : String| {
+                diags.push(Diagnostic::error(code, e.span, message));
+                Err(())
+            };
+
             match func_table.get(&*func) {
-                None => Err(format!("Function {} used without declaration", func)),
+                None => error(Code::UndefinedFunction, format!("Function {} used without declaration", func)),
                 Some(&(ref ret_type, ref arg_format)) => {
                     if arg_format.len() != args.len() {
-                        return Err(format!("Function {} expects {} arguments but received {}", func, arg_format.len(), args.len()))
+                        return error(Code::ArityMismatch, format!("Function {} expects {} arguments but received {}", func, arg_format.len(), args.len()))
                     }
-                    let types : Vec<_> = args.iter().map(|e| check_expr(e, func_table, context)).collect();
-                                                         //
-                    // Make sure all arguments typecheck
-                    match types.iter().find(|&ref x| x.is_err()) {
-                        Some(e) => return e.clone(),
-                        None => ()
+
+                    if arg_types.iter().any(|x| x.is_err()) {
+                        return Err(());
                     }
 
-                    if !types.iter().zip(arg_format.iter()).all(|(t1, t2)| subtype(t2, &t1.clone().unwrap())) {
-                       return Err(format!("Function {} was passed arguments of incorrect types", func))
+                    for ((t1, t2), arg) in arg_types.iter().zip(arg_format.iter()).zip(args.iter()) {
+                        let t1 = t1.unwrap();
+                        if !subtype(t2, &t1) {
+                            diags.push(Diagnostic::error(Code::TypeMismatch, arg.span,
+                                                         format!("Function {} expects {} but was passed {}", func, t2, t1)));
+                            return Err(());
+                        }
                     }
 
                     Ok(*ret_type)
                 }
             }
         },
-        &Expr::Elem(ref list, ref index) => {
-            let t1 = try!(check_expr(list, func_table, context));
-            let t2 = try!(check_expr(index, func_table, context));
+        &ExprKind::Elem(ref list, ref index) => {
+            let t1 = check_expr(list, func_table, context, types, diags);
+            let t2 = check_expr(index, func_table, context, types, diags);
+            let (t1, t2) = match (t1, t2) {
+                (Ok(t1), Ok(t2)) => (t1, t2),
+                _ => return Err(())
+            };
+
+            let mut error = |code : Code, message : String| {
+                diags.push(Diagnostic::error(code, e.span, message));
+                Err(())
+            };
 
             match (t1, t2) {
-                (Type::PrintableList(ref i), Type::Int(_, ref h)) => {
-                    if i < h {
-                        Err(format!("Integer type {} is too large to index into {}", t2, t1))
+                (Type::PrintableList(i), Type::Int(l, h)) => {
+                    // A list of i elements is indexed from 0 to i - 1
+                    if l < 0 || h >= i as i32 {
+                        error(Code::IndexOutOfBounds, format!("Index of type {} may be outside {}, which is indexed from 0 to {}", t2, t1, i as i32 - 1))
                     } else {
                         Ok(Type::Printable)
                     }
                 }
-                (Type::PrintableList(_), _) => Err("Lists may only be indexed by integers".to_string()),
-                _ => Err("Only lists can be indexed into".to_string())
+                (Type::PrintableList(_), _) => error(Code::TypeMismatch, "Lists may only be indexed by integers".to_string()),
+                _ => error(Code::TypeMismatch, "Only lists can be indexed into".to_string())
             }
         }
     }
 }
 
-fn check_statement(s : &Statement, func_table : &FunctionContext, context : &mut VariableContext) -> Result<Option<Type>, String> {
-    match s {
-        &Statement::Mutable(ref t, ref var, ref val) => {
-            if context.contains_key(&*var) {
-                return Err(format!("Duplicated definition of {}", var));
-            }
+// Checks that a declared integer type holds at least one value, and only
+// values that integers can take
+fn check_type(t : &Type, span : Span, diags : &mut Vec<Diagnostic>) -> Checked<()> {
+    if let &Type::Int(low, high) = t {
+        if low > high {
+            diags.push(Diagnostic::error(Code::EmptyRange, span, format!("{} holds no values", t))
+                       .with_note(None, format!("bounds are inclusive, so the lower bound {} must not be above the upper bound {}", low, high)));
+            return Err(());
+        } else if low < INT_MIN || high > INT_MAX {
+            diags.push(Diagnostic::error(Code::IntegerOutOfRange, span, format!("{} reaches outside the integers, which range from {} to {}", t, INT_MIN, INT_MAX)));
+            return Err(());
+        }
+    }
+    Ok(())
+}
 
-            let expr_type = try!(check_expr(val, func_table, &context));
-            if !subtype(t, &expr_type) {
-                return Err(format!("Assignment to {} must have type {}", var, t));
-            }
+fn check_declaration(s : &Statement, t : &Type, var : &Ident, val : &Expr, mutable : bool,
+                     func_table : &FunctionContext, context : &mut VariableContext, types : &mut Inferred, diags : &mut Vec<Diagnostic>) {
+    let valid = check_type(t, s.span, diags);
 
-            context.insert(var.clone(), (*t, true));
-            return Ok(None)
-        },
-        &Statement::Const(ref t, ref var, ref val) => {
-            if context.contains_key(&*var) {
-                return Err(format!("Duplicated definition of {}", var));
-            }
+    // A config keeps runtime values in rings of aliases, one per value
+    match t {
+        &Type::Int(_, _) | &Type::Bool => (),
+        t if mutable => diags.push(Diagnostic::error(Code::MutableType, s.span, format!("Only int and bool variables can be mutable, not {}", t))),
+        _ => ()
+    }
+
+    // Outer variables may be shadowed, but not ones from the same scope
+    if let Some(&(_, _, span)) = context.get_local(&*var) {
+        diags.push(Diagnostic::error(Code::DuplicateDefinition, s.span, format!("Duplicated definition of {}", var))
+                   .with_note(Some(span), format!("{} was first defined here", var)));
+    }
+
+    // Values can't be compared against a type that's broken itself
+    if let (Ok(()), Ok(expr_type)) = (valid, check_expr(val, func_table, &context, types, diags)) {
+        if !subtype(t, &expr_type) {
+            diags.push(Diagnostic::error(Code::TypeMismatch, val.span, format!("Assignment to {} must have type {}", var, t))
+                       .with_note(None, format!("the value has type {}", expr_type)));
+        }
+    }
+
+    // Declare the variable regardless, so later uses don't cascade
+    context.declare(var.clone(), (*t, mutable, s.span));
+}
+
+fn check_condition(cond : &Expr, func_table : &FunctionContext, context : &VariableContext, types : &mut Inferred, diags : &mut Vec<Diagnostic>) {
+    if let Ok(t) = check_expr(cond, func_table, context, types, diags) {
+        if t != Type::Bool {
+            diags.push(Diagnostic::error(Code::TypeMismatch, cond.span, format!("Conditions must have type {}", Type::Bool))
+                       .with_note(None, format!("the condition has type {}", t)));
+        }
+    }
+}
 
-            let expr_type = try!(check_expr(val, func_table, &context));
-            if !subtype(t, &expr_type) {
-                return Err(format!("Assignment to {} must have type {}", var, t));
+// Every variable an expression uses, with whether it's modified there and the
+// short-circuiting operator whose right it's on, if any
+fn expr_uses(e : &Expr, guard : Option<OpType>, uses : &mut Vec<(Ident, Span, bool, Option<OpType>)>) {
+    match &e.kind {
+        &ExprKind::Var(ref name) => uses.push((name.clone(), e.span, false, guard)),
+        &ExprKind::Unop(ref name, _) => uses.push((name.clone(), e.span, true, guard)),
+        &ExprKind::Binop(ref l, o, ref r) => {
+            expr_uses(l, guard, uses);
+            match o {
+                OpType::And | OpType::Or => expr_uses(r, guard.or(Some(o)), uses),
+                _ => expr_uses(r, guard, uses)
             }
+        },
+        &ExprKind::Elem(ref l, ref r) => {
+            expr_uses(l, guard, uses);
+            expr_uses(r, guard, uses);
+        },
+        &ExprKind::Prefix(_, ref operand) => expr_uses(operand, guard, uses),
+        &ExprKind::Call(_, ref args) => for arg in args.iter() {
+            expr_uses(arg, guard, uses);
+        },
+        _ => ()
+    }
+}
+
+// A config runs the modifications in an expression before the rest of it, so
+// they can't share the expression with other uses of their variable or sit
+// where they might not run at all
+fn check_modifications(e : &Expr, diags : &mut Vec<Diagnostic>) {
+    let mut uses = vec![];
+    expr_uses(e, None, &mut uses);
 
-            context.insert(var.clone(), (*t, false));
-            return Ok(None)
+    for &(ref name, span, _, guard) in uses.iter().filter(|u| u.2) {
+        if let Some(o) = guard {
+            diags.push(Diagnostic::error(Code::UnorderedModification, span, format!("{} cannot be modified on the right of {}", name, o)));
+        } else if let Some(&(_, other, _, _)) = uses.iter().find(|u| u.0 == *name && u.1 != span) {
+            diags.push(Diagnostic::error(Code::UnorderedModification, span, format!("{} is modified here and used elsewhere in the same expression", name))
+                       .with_note(Some(other), format!("{} is also used here", name)));
+        }
+    }
+}
+
+// What a statement is checked against: the function it's in, and whether
+// there is a loop to break out of
+#[derive(Clone, Copy)]
+struct Control<'a> {
+    function: &'a Function,
+    in_loop: bool
+}
+
+// The ways control can leave a statement other than by returning, each
+// explained by a note.  No ends means the statement never completes.
+struct Exits {
+    ends: Vec<Note>,
+    breaks: Vec<Note>
+}
+
+impl Exits {
+    fn falls(s : &Statement) -> Exits {
+        Exits { ends: vec![Note { span: Some(s.span), message: "this path does not return".to_string() }], breaks: vec![] }
+    }
+
+    fn diverges() -> Exits {
+        Exits { ends: vec![], breaks: vec![] }
+    }
+}
+
+fn check_statement(s : &Statement, control : Control, func_table : &FunctionContext, context : &mut VariableContext,
+                   types : &mut Inferred, diags : &mut Vec<Diagnostic>) -> Exits {
+    match &s.kind {
+        &StatementKind::Mutable(_, _, ref e) | &StatementKind::Const(_, _, ref e) | &StatementKind::Assign(_, ref e) |
+        &StatementKind::Return(ref e) | &StatementKind::Expr(ref e) |
+        &StatementKind::If(ref e, _, _) | &StatementKind::While(ref e, _) => check_modifications(e, diags),
+        _ => ()
+    }
+
+    match &s.kind {
+        &StatementKind::Mutable(ref t, ref var, ref val) => {
+            check_declaration(s, t, var, val, true, func_table, context, types, diags);
+            Exits::falls(s)
+        },
+        &StatementKind::Const(ref t, ref var, ref val) => {
+            check_declaration(s, t, var, val, false, func_table, context, types, diags);
+            Exits::falls(s)
         },
-        &Statement::Assign(ref var, ref val) => {
+        &StatementKind::Assign(ref var, ref val) => {
+            let expr_type = check_expr(val, func_table, &context, types, diags);
+
             match context.get(&*var) {
-                Some(&(_, false)) => return Err(format!("Attempted to modify immutable variable {}", var)),
-                None => return Err(format!("Undeclared variable {}", var)),
-                Some(&(ref t, _)) => {
-                    let expr_type = try!(check_expr(val, func_table, &context));
-                    if !subtype(t, &expr_type) {
-                        return Err(format!("Assignment to {} must have type {}", var, t));
+                Some(&(_, false, span)) => diags.push(
+                    Diagnostic::error(Code::ImmutableAssignment, s.span, format!("Attempted to modify immutable variable {}", var))
+                        .with_note(Some(span), format!("{} is declared here without mut", var))),
+                None => diags.push(Diagnostic::error(Code::UndefinedVariable, s.span, format!("Undeclared variable {}", var))),
+                Some(&(ref t, _, _)) => {
+                    if let Ok(expr_type) = expr_type {
+                        if !subtype(t, &expr_type) {
+                            diags.push(Diagnostic::error(Code::TypeMismatch, val.span, format!("Assignment to {} must have type {}", var, t))
+                                       .with_note(None, format!("the value has type {}", expr_type)));
+                        }
                     }
                 }
             }
 
-            return Ok(None)
+            Exits::falls(s)
         },
-        &Statement::Block(ref stmts) => {
-            // TODO: scoped contexts need to occur here
+        &StatementKind::Block(ref stmts) => {
+            context.push();
 
-            // TODO: match return types in this function rather than check_function
+            // Control only reaches the end through the last statement, but
+            // any of them may break
+            let mut exits = Exits::falls(s);
+            let mut diverged : Option<Span> = None;
             for stmt in stmts.iter() {
-                match check_statement(stmt, func_table, context) {
-                    Ok(Some(x)) => return Ok(Some(x)),
-                    Err(s) => return Err(s),
-                    _ => ()
+                if let Some(span) = diverged.take() {
+                    diags.push(Diagnostic::warning(Code::UnreachableCode, stmt.span, "Unreachable statement".to_string())
+                               .with_note(Some(span), "control never continues past this".to_string()));
+                }
+
+                let reachable = !exits.ends.is_empty();
+                let stmt_exits = check_statement(stmt, control, func_table, context, types, diags);
+                if reachable {
+                    if stmt_exits.ends.is_empty() {
+                        diverged = Some(stmt.span);
+                    }
+                    exits.ends = stmt_exits.ends;
+                    exits.breaks.extend(stmt_exits.breaks);
                 }
             }
 
-            return Ok(None);
+            context.pop();
+            exits
+        },
+        &StatementKind::Break => {
+            if !control.in_loop {
+                diags.push(Diagnostic::error(Code::BreakOutsideLoop, s.span, "Break used outside of a loop".to_string()));
+            }
+            Exits { ends: vec![], breaks: vec![Note { span: Some(s.span), message: "the loop can be left here".to_string() }] }
+        },
+        &StatementKind::Loop(ref body) => {
+            // Control can only leave the loop by breaking or returning
+            let body = check_scoped(&*body, Control { in_loop: true, ..control }, func_table, context, types, diags);
+            Exits { ends: body.breaks, breaks: vec![] }
+        },
+        &StatementKind::While(ref cond, ref body) => {
+            check_condition(cond, func_table, context, types, diags);
+
+            let body = check_scoped(&*body, Control { in_loop: true, ..control }, func_table, context, types, diags);
+            let mut ends = vec![Note { span: Some(cond.span), message: "the loop ends once this is false".to_string() }];
+            ends.extend(body.breaks);
+            Exits { ends: ends, breaks: vec![] }
         },
-        &Statement::Break => {
-            return Ok(None);
+        &StatementKind::If(ref cond, ref then, ref otherwise) => {
+            check_condition(cond, func_table, context, types, diags);
+
+            let mut exits = check_scoped(&*then, control, func_table, context, types, diags);
+            let other = match otherwise {
+                &Some(ref otherwise) => check_scoped(&*otherwise, control, func_table, context, types, diags),
+                &None => Exits { ends: vec![Note { span: Some(cond.span), message: "nothing is returned when this is false".to_string() }], breaks: vec![] }
+            };
+
+            exits.ends.extend(other.ends);
+            exits.breaks.extend(other.breaks);
+            exits
         },
-        &Statement::Input(ref branches) => {
-            let mut keys = HashSet::new();
+        &StatementKind::Input(ref branches) => {
+            let mut keys = HashMap::new();
+            let mut exits = Exits::diverges();
 
             for &(ref key, ref arm) in branches.iter() {
-                if !keys.insert(key) {
-                    return Err(format!("Duplicated branch {:?}", key));
+                if let Some(span) = keys.insert(key, arm.span) {
+                    diags.push(Diagnostic::error(Code::DuplicateBranch, arm.span, format!("Duplicated branch {:?}", key))
+                               .with_note(Some(span), format!("{:?} is first handled here", key)));
                 }
 
-                // TODO: Check arm returns
-                try!(check_statement(&*arm, func_table, context));
+                if default_bind(key).is_none() {
+                    diags.push(Diagnostic::warning(Code::NoDefaultBind, arm.span, format!("The game doesn't bind {:?}, so it does nothing once the input is over", key))
+                               .with_note(None, format!("an autoexec run after the config can define {} to give it a bind", restore_alias(key))));
+                }
+
+                // Point at the arm as a whole rather than somewhere inside it
+                let arm_exits = check_scoped(&*arm, control, func_table, context, types, diags);
+                if !arm_exits.ends.is_empty() {
+                    exits.ends.push(Note { span: Some(arm.span), message: format!("the {:?} arm does not return", key) });
+                }
+                exits.breaks.extend(arm_exits.breaks);
             }
-            return Ok(None);
+            exits
         },
-        &Statement::Return(ref expr) => {
-            return Ok(Some(try!(check_expr(&*expr, func_table, context))));
+        &StatementKind::Return(ref expr) => {
+            let f = control.function;
+            let expr_type = check_expr(&*expr, func_table, context, types, diags);
+
+            match (&f.ret, expr_type) {
+                (&None, _) => diags.push(Diagnostic::error(Code::UnexpectedReturn, s.span, format!("Function {} returns a value but has no return type", f.name))
+                                         .with_note(Some(f.span), format!("{} is declared here", f.name))),
+                (&Some(ref t), Ok(expr_type)) => {
+                    if !subtype(t, &expr_type) {
+                        diags.push(Diagnostic::error(Code::TypeMismatch, expr.span, format!("Function {} must return {}", f.name, t))
+                                   .with_note(None, format!("the value has type {}", expr_type)));
+                    }
+                },
+                // An invalid return still returns, so don't report it twice
+                (_, Err(())) => ()
+            }
+
+            Exits::diverges()
         },
-        &Statement::Expr(ref expr) => {
-            try!(check_expr(&*expr, func_table, context));
-            return Ok(None);
+        &StatementKind::Expr(ref expr) => {
+            let _ = check_expr(&*expr, func_table, context, types, diags);
+            Exits::falls(s)
         }
     }
-    panic!("Internal Error")
 }
 
-fn check_function(f : &Function, func_table : &FunctionContext) -> Result<(), String> {
+// Checks a statement in a scope of its own, like an input arm or the body
+// of a loop, even when it isn't a block
+fn check_scoped(s : &Statement, control : Control, func_table : &FunctionContext, context : &mut VariableContext,
+                types : &mut Inferred, diags : &mut Vec<Diagnostic>) -> Exits {
+    context.push();
+    let exits = check_statement(s, control, func_table, context, types, diags);
+    context.pop();
+    exits
+}
+
+fn check_function(f : &Function, func_table : &FunctionContext, types : &mut Inferred, diags : &mut Vec<Diagnostic>) {
     let mut context = VariableContext::new();
 
     // Add the local variables
     for &(ref t, ref var) in f.args.iter() {
-        context.insert(var.clone(), (*t, false));
-    }
-
-    // Check that statements are fine and the function always returns correctly
-    match (check_statement(&f.body, func_table, &mut context), &f.ret) {
-        (Err(s), _) => return Err(s),
-        (Ok(Some(_)), &None) => return Err(format!("Function {} does not return", f.name)),
-        (Ok(None), &Some(_)) => return Err(format!("Function {} returns unexpectedly", f.name)),
-        (Ok(None), &None) => return Ok(()),
-        (Ok(Some(ref t1)), &Some(ref t2)) => {
-            if !subtype(t2, t1) {
-                return Err(format!("Function {} returned {} when {} was expected", f.name, t1, t2));
-            }
-        }
+        let _ = check_type(t, f.span, diags);
+        context.declare(var.clone(), (*t, false, f.span));
+    }
+    if let Some(ref t) = f.ret {
+        let _ = check_type(t, f.span, diags);
     }
 
-    return Ok(());
-}
-
-fn parse_function(f : &Function, func_table : &mut FunctionContext) -> Result<(), String> {
-    if func_table.contains_key(&f.name) {
-        return Err(format!("Function {} is already defined", f.name));
+    // Check that statements are fine and every path returns
+    let exits = check_statement(&f.body, Control { function: f, in_loop: false }, func_table, &mut context, types, diags);
+    if let Some(ref t) = f.ret {
+        if !exits.ends.is_empty() {
+            let mut d = Diagnostic::error(Code::MissingReturn, f.span, format!("Function {} does not return a {} on every path", f.name, t));
+            d.notes = exits.ends;
+            diags.push(d);
+        }
     }
+}
 
+fn parse_function(f : &Function, func_table : &mut FunctionContext) {
     let ret_type = if let Some(t) = f.ret { t } else { Type::Bottom };
     func_table.insert(f.name.clone(), (ret_type, f.args.iter().map(|&(ref x, _)| *x).collect()));
-    return Ok(());
 }
 
-pub fn check(t : AST) -> Result<(), String> {
+// Checks every function, returning all of the problems found
+#[cfg(test)]
+pub fn check(t : &AST) -> Vec<Diagnostic> {
+    infer(t).0
+}
+
+// Checks a program, also returning the types inferred for its expressions
+pub fn infer(t : &AST) -> (Vec<Diagnostic>, HashMap<Span, Type>) {
+    let mut diags = vec![];
+    let mut types = Inferred::new();
     let mut func_table = FunctionContext::new();
 
     for builtin in vec!["say", "say_student"] {
@@ -232,13 +559,121 @@ pub fn check(t : AST) -> Result<(), String> {
     }
 
     // Build the function table to allow forward references
+    let mut defined : HashMap<&Ident, Span> = HashMap::new();
     for func in &t.0 {
-        try!(parse_function(func, &mut func_table));
+        if func_table.contains_key(&func.name) {
+            let mut d = Diagnostic::error(Code::DuplicateDefinition, func.span, format!("Function {} is already defined", func.name));
+            if let Some(span) = defined.get(&func.name) {
+                d = d.with_note(Some(*span), format!("{} was first defined here", func.name));
+            }
+            diags.push(d);
+            continue;
+        }
+
+        parse_function(func, &mut func_table);
+        defined.insert(&func.name, func.span);
     }
 
     for func in &t.0 {
-        try!(check_function(func, &func_table));
+        check_function(func, &func_table, &mut types, &mut diags);
+    }
+
+    return (diags, types);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    use parser;
+
+    // The code of every problem found in the source, in the order found
+    fn codes(source : &str) -> Vec<Code> {
+        check(&parser::parse(source.to_string()).unwrap()).iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn empty_range() {
+        assert_eq!(codes("main() { int<9, 2> x = 3; }"), vec![Code::EmptyRange]);
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        assert_eq!(codes("main() { break; }"), vec![Code::BreakOutsideLoop]);
+        assert_eq!(codes("main() { loop { break; } }"), vec![]);
+    }
+
+    #[test]
+    fn missing_return_names_arm() {
+        let ast = parser::parse("int f() { input { W => return 1; S => say(\"s\"); } } main() { }".to_string()).unwrap();
+        let diags = check(&ast);
+        assert_eq!(diags.iter().map(|d| d.code).collect::<Vec<_>>(), vec![Code::MissingReturn]);
+        assert!(diags[0].notes.iter().any(|n| n.message == "the S arm does not return"));
+    }
+
+    #[test]
+    fn duplicate_definitions() {
+        assert_eq!(codes("main() { int x = 1; int x = 2; }"), vec![Code::DuplicateDefinition]);
+
+        // Sibling and nested scopes may reuse a name
+        assert_eq!(codes("main() { { int x = 1; } { int x = 2; } int x = 3; { int x = 4; } }"), vec![]);
     }
 
-    return Ok(());
+    #[test]
+    fn unordered_modification() {
+        assert_eq!(codes("main() { mut int<0, 3> x = 0; int<0, 3> y = x++; if (x-- == 1) { x++; } }"), vec![]);
+        assert_eq!(codes("main() { mut int<0, 3> x = 0; say(\"\" + x + x++); }"), vec![Code::UnorderedModification]);
+        assert_eq!(codes("main() { mut int<0, 3> x = 0; if (true || x++ == 1) { } }"), vec![Code::UnorderedModification]);
+    }
+
+    #[test]
+    fn unsafe_list_text() {
+        assert_eq!(codes("main() { say([\"a\", \"b;c\"][0]); }"), vec![Code::UnsafeText]);
+        assert_eq!(codes("main() { say([\"a\", \"b/c\"][0]); }"), vec![]);
+    }
+
+    #[test]
+    fn mutable_type() {
+        assert_eq!(codes("main() { mut bool b = true; mut int<0, 1> x = 0; string s = \"s\"; }"), vec![]);
+        assert_eq!(codes("main() { mut string s = \"s\"; }"), vec![Code::MutableType]);
+    }
+
+    #[test]
+    fn no_default_bind() {
+        assert_eq!(codes("main() { input { F1 => say(\"f1\"); W => say(\"w\"); } }"), vec![Code::NoDefaultBind]);
+    }
+
+    #[test]
+    fn key_positions() {
+        assert_eq!(codes("key f() { return F1; } main() { key k = ALT+Q; if (k == W || f() != E) { } }"), vec![]);
+
+        // A key name anywhere else is an undeclared variable
+        assert_eq!(codes("main() { say(\"x\" + E); }"), vec![Code::UndefinedVariable]);
+        assert_eq!(codes("main() { int<0, 3> x = SPACE; }"), vec![Code::UndefinedVariable]);
+    }
+
+    #[test]
+    fn modifier_chord() {
+        let d = match parser::parse("main() { input { CTRL+ALT => say(\"a\"); } }".to_string()) {
+            Err(d) => d,
+            Ok(_) => panic!("CTRL+ALT parsed as a key")
+        };
+        assert_eq!(d.message, "CTRL+ALT is not a key, since ALT can't be held with another modifier");
+        assert_eq!((d.span.lo, d.span.hi), (17, 25));
+        assert!(parser::parse("main() { input { CTRL+Q => say(\"a\"); } }".to_string()).is_ok());
+    }
+
+    #[test]
+    fn unknown_escape() {
+        let d = lexer::lex("main() { say(\"\\q\"); }").unwrap_err();
+        assert_eq!(d.code, Code::SyntaxError);
+        assert_eq!(d.message, "Unknown escape sequence");
+    }
+
+    #[test]
+    fn unsafe_text() {
+        assert_eq!(codes("main() { say(\"a \\\"quote\\\"\"); say(\"a;b\"); say(\"http://a.link\"); }"),
+                   vec![Code::UnsafeText, Code::UnsafeText, Code::UnsafeText]);
+        assert_eq!(codes("main() { say(\"a/b\"); }"), vec![]);
+    }
 }
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use ast::KeyType;
+use codegen::{game_command, key_name};
+use interp::Message;
+use ir::Action;
+#[cfg(test)]
+use ir::Program;
+
+// Guards against aliases that recurse forever, well before the emulator
+// itself runs out of stack
+static MAX_DEPTH : usize = 512;
+
+// Emulates the subset of the console language that the code generator emits.
+// Programs can also be loaded without going through text, in which case
+// their states act like aliases.
+pub struct Console {
+    aliases: HashMap<String, String>,
+    states: HashMap<String, Vec<Action>>,
+    binds: HashMap<String, String>,
+    pub messages: Vec<Message>
+}
+
+// Splits a line into commands on semicolons outside of quotes, dropping
+// any trailing comment
+fn split_commands(line : &str) -> Vec<&str> {
+    let mut commands = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    let bytes = line.as_bytes();
+
+    for (i, &c) in bytes.iter().enumerate() {
+        match c {
+            b'"' => quoted = !quoted,
+            b';' if !quoted => {
+                commands.push(&line[start..i]);
+                start = i + 1;
+            },
+            b'/' if !quoted && bytes.get(i + 1) == Some(&b'/') => {
+                commands.push(&line[start..i]);
+                return commands;
+            },
+            _ => ()
+        }
+    }
+
+    commands.push(&line[start..]);
+    commands
+}
+
+// Splits a command into arguments, keeping quoted text together
+fn split_args(command : &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in command.chars() {
+        match c {
+            '"' => {
+                if quoted {
+                    args.push(current.clone());
+                    current.clear();
+                }
+                quoted = !quoted;
+            },
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    args.push(current.clone());
+                    current.clear();
+                }
+            },
+            c => current.push(c)
+        }
+    }
+
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+// The raw text following the command name, as used by say.  Only spaces are
+// trimmed, since some colour codes count as whitespace.
+fn arg_text(command : &str, name : &str) -> String {
+    let text = command.trim_matches(' ')[name.len()..].trim_matches(' ');
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        text[1..text.len() - 1].to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            aliases: HashMap::new(),
+            states: HashMap::new(),
+            binds: HashMap::new(),
+            messages: vec![]
+        }
+    }
+
+    fn command(&mut self, command : &str, depth : usize) -> Result<(), String> {
+        let args = split_args(command);
+        if args.is_empty() {
+            return Ok(());
+        }
+
+        match &*args[0] {
+            "alias" => {
+                if args.len() < 2 {
+                    return Err(format!("Malformed alias: {}", command.trim()));
+                }
+                let body = args[2..].join(" ");
+                self.aliases.insert(args[1].clone(), body);
+            },
+            "bind" => {
+                if args.len() != 3 {
+                    return Err(format!("Malformed bind: {}", command.trim()));
+                }
+                self.binds.insert(args[1].to_lowercase(), args[2].clone());
+            },
+            "unbind" => {
+                if args.len() != 2 {
+                    return Err(format!("Malformed unbind: {}", command.trim()));
+                }
+                self.binds.remove(&args[1].to_lowercase());
+            },
+            "say" => self.messages.push(Message::Say(arg_text(command, "say"))),
+            "say_team" => self.messages.push(Message::SayTeam(arg_text(command, "say_team"))),
+            name if game_command(name) => (),
+            name => try!(self.run_name(name, depth))
+        }
+
+        Ok(())
+    }
+
+    fn run_name(&mut self, name : &str, depth : usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("Alias recursion is too deep".to_string());
+        }
+
+        if let Some(body) = self.aliases.get(name).cloned() {
+            return self.line(&body, depth + 1);
+        }
+
+        let actions = match self.states.get(name) {
+            Some(actions) => actions.clone(),
+            None => return Err(format!("Unknown command {}", name))
+        };
+        for action in actions.iter() {
+            try!(self.act(action, depth + 1));
+        }
+        Ok(())
+    }
+
+    // Performs an action as the command it's emitted as would
+    fn act(&mut self, action : &Action, depth : usize) -> Result<(), String> {
+        match action {
+            &Action::Say(ref text) => self.messages.push(Message::Say(text.clone())),
+            &Action::SayTeam(ref text) => self.messages.push(Message::SayTeam(text.clone())),
+            &Action::Bind(ref key, ref state) => {
+                self.binds.insert(key_name(key).to_lowercase(), state.clone());
+            },
+            &Action::SetAlias(ref slot, ref state) => {
+                self.aliases.insert(slot.clone(), state.clone());
+            },
+            &Action::Run(ref name) => try!(self.run_name(name, depth)),
+            &Action::Game(_) => ()
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line : &str, depth : usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("Alias recursion is too deep".to_string());
+        }
+
+        for command in split_commands(line) {
+            try!(self.command(command, depth));
+        }
+        Ok(())
+    }
+
+    // Runs every line of a config, as the exec command would
+    pub fn exec(&mut self, config : &str) -> Result<(), String> {
+        for line in config.lines() {
+            try!(self.line(line, 0));
+        }
+        Ok(())
+    }
+
+    // Defines the program's states and runs its entry
+    #[cfg(test)]
+    pub fn load(&mut self, p : &Program) -> Result<(), String> {
+        for state in p.states.iter() {
+            self.states.insert(state.name.clone(), state.actions.clone());
+        }
+        for action in p.entry.iter() {
+            try!(self.act(action, 0));
+        }
+        Ok(())
+    }
+
+    // Key names are case insensitive
+    pub fn press(&mut self, key : &str) -> Result<(), String> {
+        match self.binds.get(&key.to_lowercase()).cloned() {
+            Some(command) => self.line(&command, 0),
+            None => Ok(())
+        }
+    }
+}
+
+// Execs a config and then presses each of the keys in turn, returning the
+// resulting chat
+pub fn run(config : &str, keys : &[KeyType]) -> Result<Vec<Message>, String> {
+    let mut console = Console::new();
+    try!(console.exec(config));
+
+    for key in keys.iter() {
+        try!(console.press(&key_name(key)));
+    }
+
+    return Ok(console.messages);
+}
+
+// Runs a program directly rather than as config text, which checks the
+// program apart from how it's written out
+#[cfg(test)]
+pub fn simulate(p : &Program, keys : &[KeyType]) -> Result<Vec<Message>, String> {
+    let mut console = Console::new();
+    try!(console.load(p));
+
+    for key in keys.iter() {
+        try!(console.press(&key_name(key)));
+    }
+
+    return Ok(console.messages);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::KeyType;
+    use codegen;
+    use diagnostic::Severity;
+    use inline;
+    use interp;
+    use interp::Message;
+    use ir;
+    use optimize;
+    use parser;
+    use typechecker;
+
+    #[test]
+    fn quoting() {
+        let mut console = Console::new();
+        console.exec("alias greet \"say hello; say world\" // comment\nbind w greet\nbind s \"say a;b\"").unwrap();
+        console.press("w").unwrap();
+        assert_eq!(console.messages, vec![Message::Say("hello".to_string()), Message::Say("world".to_string())]);
+
+        // Quotes only protect the semicolon until the binding runs
+        assert_eq!(console.press("s"), Err("Unknown command b".to_string()));
+    }
+
+    // Keys go back to what the game binds them to once the input is over,
+    // unless an autoexec gave them a bind of its own.  Keys the game doesn't
+    // bind are never unbound, since that would lose the player's bind.
+    #[test]
+    fn restores_default_binds() {
+        let ast = parser::parse("main() { input { W => say(\"w\"); F1 => say(\"f1\"); } }".to_string()).unwrap();
+        let (program, _) = optimize::optimize(inline::inline(&ast).unwrap(), 0).unwrap();
+
+        let mut console = Console::new();
+        console.exec(&codegen::emit(&program).to_string()).unwrap();
+        console.press("F1").unwrap();
+        console.press("w").unwrap();
+
+        assert_eq!(console.messages, vec![Message::Say("f1".to_string())]);
+        assert_eq!(console.binds["w"], "cw.key.w");
+        assert_eq!(console.aliases.get("cw.key.w.default"), Some(&"dota_ability_execute 1".to_string()));
+        assert_eq!(console.binds["f1"], "cw.key.F1");
+        assert_eq!(console.aliases.get("cw.key.F1.default"), Some(&"".to_string()));
+
+        console.exec("alias cw.key.w \"say mine\"").unwrap();
+        console.press("w").unwrap();
+        assert_eq!(console.messages[1..], [Message::Say("mine".to_string())]);
+    }
+
+    // Every key sequence up to the given length
+    fn sequences(length : usize) -> Vec<Vec<KeyType>> {
+        let mut all = vec![vec![]];
+        let mut last = vec![vec![]];
+        for _ in 0..length {
+            let mut next = vec![];
+            for seq in last.iter() {
+                for key in ["W", "A", "S", "D", "F1", "ALT+Q"].iter() {
+                    let mut seq : Vec<KeyType> = seq.clone();
+                    seq.push(KeyType::named(key).unwrap());
+                    next.push(seq);
+                }
+            }
+            all.extend(next.iter().cloned());
+            last = next;
+        }
+        all
+    }
+
+    // The compiled config must chat exactly like the interpreter at every
+    // optimization level, and fail wherever the interpreter reports an error
+    fn check_agreement(source : &str) {
+        let ast = parser::parse(source.to_string()).unwrap();
+        assert!(typechecker::check(&ast).iter().all(|d| d.severity == Severity::Warning));
+
+        for level in 0..4 {
+            let (program, _) = optimize::optimize(inline::inline(&ast).unwrap(), level).unwrap();
+            assert_eq!(ir::validate(&program), Ok(()));
+            let config = codegen::emit(&program).to_string();
+
+            let mut accepted = 0;
+            for keys in sequences(3) {
+                match interp::run(&ast, &keys) {
+                    Ok(expected) => {
+                        accepted += 1;
+                        assert_eq!(run(&config, &keys), Ok(expected.clone()), "-O{} keys {:?}", level, keys);
+                        assert_eq!(simulate(&program, &keys), Ok(expected), "-O{} keys {:?}", level, keys);
+                    },
+                    Err(e) => {
+                        assert!(run(&config, &keys).is_err(), "-O{} keys {:?} should fail with {}", level, keys, e);
+                        assert!(simulate(&program, &keys).is_err(), "-O{} keys {:?} should fail with {}", level, keys, e);
+                    }
+                }
+            }
+
+            // A program the interpreter always rejects checks nothing
+            assert!(accepted > 0, "-O{} rejects every sequence", level);
+        }
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        check_agreement(include_str!("../tests/bigfunc.cw"));
+        check_agreement(include_str!("../tests/booltest.cw"));
+        check_agreement(include_str!("../tests/calltest.cw"));
+        check_agreement(include_str!("../tests/colortest.cw"));
+        check_agreement(include_str!("../tests/countertest.cw"));
+        check_agreement(include_str!("../tests/commenttest.cw"));
+        check_agreement(include_str!("../tests/confirmtest.cw"));
+        check_agreement(include_str!("../tests/comparetest.cw"));
+        check_agreement(include_str!("../tests/functest.cw"));
+        check_agreement(include_str!("../tests/hygienetest.cw"));
+        check_agreement(include_str!("../tests/hotkeytest.cw"));
+        check_agreement(include_str!("../tests/identtest.cw"));
+        check_agreement(include_str!("../tests/iftest.cw"));
+        check_agreement(include_str!("../tests/inlinetest.cw"));
+        check_agreement(include_str!("../tests/inputtest.cw"));
+        check_agreement(include_str!("../tests/littlefunc.cw"));
+        check_agreement(include_str!("../tests/looptest.cw"));
+        check_agreement(include_str!("../tests/menutest.cw"));
+        check_agreement(include_str!("../tests/modifytest.cw"));
+        check_agreement(include_str!("../tests/negativetest.cw"));
+        check_agreement(include_str!("../tests/precedencetest.cw"));
+        check_agreement(include_str!("../tests/scopetest.cw"));
+        check_agreement(include_str!("../tests/spintest.cw"));
+        check_agreement(include_str!("../tests/stringtest.cw"));
+        check_agreement(include_str!("../tests/wraptest.cw"));
+    }
+}
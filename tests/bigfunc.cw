@@ -4,7 +4,7 @@ main() {
     string<5> z = ["this", "is", "a", "test", "case"];
 
     {
-        int<0,16> a = 6;
+        int<0,15> a = 6;
         int b = 5+( 6+7+8)+9;
         z[4];
 
@@ -14,7 +14,7 @@ main() {
         }
     }
 
-    int<0, 255> w = 3;
+    int<0, 254> w = 3;
 }
 
 string yolo(string x) {
@@ -0,0 +1,21 @@
+// Booleans only known while the program runs
+main() {
+	mut int<0, 3> x = 0;
+	mut bool seen = false;
+	loop {
+		input {
+			W => {
+				x++;
+				seen = true;
+			}
+			S => {
+				bool b = x == 0 && true;
+				if (b) { say("at zero"); } else { say("moved"); }
+			}
+			D => break;
+		}
+	}
+	if (seen || x == 2) { say("seen"); } else { say("never moved"); }
+	seen = !seen;
+	if (seen) { say("flipped"); }
+}
@@ -0,0 +1,26 @@
+int<0,3> ask(int<0,3> limit) {
+    loop {
+        input {
+            W => return 1;
+            A => {
+                if (limit > 1) return 2;
+                say("too big");
+            }
+        }
+    }
+}
+
+pause() {
+    say("press S");
+    input {
+        S => say("resumed");
+    }
+}
+
+main() {
+    int<0,3> first = ask(3);
+    say("first " + first);
+    pause();
+    say("second " + ask(1));
+    say("first again " + first);
+}
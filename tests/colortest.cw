@@ -0,0 +1,16 @@
+// Colour codes in chat
+main() {
+	color warn = color(250, 60, 60);
+	say(warn + "low health");
+	say("mana " + (blue + "full"));
+	string orange = "fruit"; // Colour names are free to name variables
+	say(orange + (grey + "!"));
+	loop {
+		input {
+			W => say(lightgreen + "ready" + (white + " go"));
+			A => say(darkblue + "" + (lime + "\nrunes"));
+			D => break;
+		}
+	}
+	say(magenta + "bye");
+}
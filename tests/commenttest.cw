@@ -0,0 +1,14 @@
+/* Comments and tabs may go anywhere whitespace can */
+main() {
+	// Chatting is a builtin
+	mut int<0, /* inclusive */ 3> x = 1; // trailing
+	if (x /* mid */ == 1) {
+		say("one"); /* block */
+		// last in block
+	}
+	input {
+		W => say("w"); // after a case
+		S => { x = 2; say("s"); }
+	}
+}
+// the end
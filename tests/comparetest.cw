@@ -0,0 +1,22 @@
+main() {
+    mut int<0,3> presses = 0;
+    key favourite = W;
+    color team = red;
+
+    input {
+        W => presses++;
+        A => say("a");
+    }
+    input {
+        W => presses++;
+        S => say("s");
+    }
+
+    if (presses >= 2 && favourite == W) {
+        say("pressed W twice");
+    } else if (presses == 1 || !(team != red)) {
+        say("W presses: " + presses);
+    }
+
+    if (presses + 1 < 2) say("no W");
+}
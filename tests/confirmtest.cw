@@ -0,0 +1,15 @@
+// Functions that wait for input share one copy when they pass booleans
+bool confirm(bool strict) {
+	input {
+		W => return true;
+		S => return !strict;
+	}
+}
+
+main() {
+	if (confirm(true)) {
+		say("first");
+	}
+	bool again = confirm(false);
+	if (again) { say("second"); } else { say("no"); }
+}
@@ -0,0 +1,11 @@
+main() {
+    mut int<0, 3> x = 1;
+    x++;
+    x++;
+    say("x is " + x);
+    int<0, 2> z = 2;
+    x = z + 1;
+    int<0, 3> y = x;
+    x--;
+    say("y is " + y);
+}
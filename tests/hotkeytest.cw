@@ -0,0 +1,14 @@
+// Ability hotkeys alongside movement
+main() {
+	int<0, 3> N = 2; // Key names are free to name variables
+	loop {
+		input {
+			F1 => say("selected hero " + N);
+			ALT+Q => say("Q is ready");
+			KEY_1 => say("item one");
+			MOUSE4 => say("camera");
+			W => break;
+		}
+	}
+	say("done");
+}
@@ -0,0 +1,21 @@
+// User names that look like the ones the compiler generates
+int<0, 14> f(int<0, 9> a) {
+	int<0, 14> b = a + 5;
+	return b;
+}
+
+main() {
+	int<0, 9> f_b_1 = 1;
+	int<0, 14> r = f(2);
+	say("mine " + f_b_1);
+	say("r " + r);
+
+	mut int<0, 9> x = 3;
+	mut int<0, 9> x_1 = 4;
+	input {
+		W => x++;
+		S => x_1--;
+	}
+	say("x " + x);
+	say("x_1 " + x_1);
+}
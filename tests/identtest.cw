@@ -0,0 +1,17 @@
+// Identifiers may start with keywords, keys and colors
+int<0, 5> reduce(int<0, 2> Ammo) {
+	return Ammo + 2;
+}
+
+main() {
+	mut int<0, 8> Ammo = reduce(1);
+	string redText = "red";
+	bool trueish = true;
+	input {
+		W => if (trueish) { say(redText); }
+		D => { Ammo = 4; say("d"); }
+	}
+	if (Ammo == 3) {
+		say("three");
+	}
+}
@@ -0,0 +1,31 @@
+main() {
+    bool verbose = true;
+    bool quiet = false;
+
+    if (verbose) say("starting"); else say("silent");
+
+    if (quiet) {
+        say("never");
+    }
+
+    mut int<0,2> picks = 0;
+    if (verbose) {
+        input {
+            W => picks++;
+            A => {
+                picks++;
+                picks++;
+            }
+        }
+        say("picked");
+    } else {
+        say("skipped");
+    }
+
+    if (quiet) say("nothing"); else input {
+        S => say("stopped");
+        D => picks--;
+    }
+
+    say("picks: " + picks);
+}
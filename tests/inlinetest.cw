@@ -0,0 +1,36 @@
+string greet(string name) {
+    return "hello " + name;
+}
+
+int<0,3> ask(string prompt) {
+    say(prompt);
+    loop {
+        input {
+            W => return 1;
+            A => return 2;
+            S => say("pick W or A");
+        }
+    }
+}
+
+int<0,4> pick(int<0,3> choice) {
+    if (choice == 1) {
+        say("first");
+        return 3;
+    }
+    int<0,4> other = choice + 1;
+    return other;
+}
+
+announce(int<0,3> n) {
+    int<0,3> shown = n;
+    say(greet("player " + shown));
+}
+
+main() {
+    int<0,3> shown = 0;
+    announce(2);
+    int<0,3> answer = ask("which?");
+    say("answer " + pick(answer));
+    say("shown " + shown);
+}
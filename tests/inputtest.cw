@@ -1,5 +1,5 @@
 main() {
-	int<0, 7> x = 6;
+	int<0, 6> x = 6;
 	input {
 		A => {
 			say("dank");
@@ -0,0 +1,32 @@
+main() {
+    mut int<0,3> presses = 0;
+    say("pick a side");
+
+    loop {
+        input {
+            W => {
+                presses++;
+                say("up");
+            }
+            A => say("left");
+            D => break;
+        }
+
+        if (presses == 2) {
+            say("up twice");
+            break;
+        }
+    }
+
+    mut int<0,3> count = 0;
+    while (count < presses) {
+        say("counting");
+        count++;
+    }
+
+    while (count > 0) input {
+        S => count--;
+        D => break;
+    }
+    say("done");
+}
@@ -0,0 +1,19 @@
+main() {
+    mut int<0, 7> presses = 0;
+    say("pick a side");
+    input {
+        A => {
+            presses++;
+            say("left");
+            input {
+                W => say("left and up");
+                S => {
+                    presses++;
+                    say("left and down");
+                }
+            }
+        }
+        D => say("right");
+    }
+    say("presses: " + presses);
+}
@@ -0,0 +1,16 @@
+// Modifications used as values read what they leave behind
+main() {
+	mut int<0, 3> x = 0;
+	int<0, 3> y = x++;
+	say("y is " + y);
+	loop {
+		input {
+			W => if (x++ == 3) { say("wrapped"); } else { say("x is " + x); }
+			S => say("down to " + x--);
+			D => break;
+		}
+	}
+	while (x-- > 0) {
+		say("counting " + x);
+	}
+}
@@ -0,0 +1,14 @@
+// Nudges the camera left and right of where it started
+main() {
+	mut int<-3, 3> nudge = 0;
+	int<-5, 5> step = -2;
+	say(("step " + step * -1) + (" from " + (0 - step)));
+	loop {
+		input {
+			A => nudge--;
+			D => nudge++;
+			S => nudge = -nudge;
+			W => say("nudged " + nudge);
+		}
+	}
+}
@@ -0,0 +1,14 @@
+// Arithmetic groups from the left and multiplication binds tightest
+main() {
+	string<2> z = ["p", "q"];
+	mut int<0, 3> n = 1;
+	loop {
+		say("n " + n + " is " + (10 - n - 2) + " and " + (n * 2 + 1) + " and " + (2 - -n - 1));
+		say(z[0] + "x" + z[1] * n);
+		input {
+			W => n++;
+			S => n--;
+			D => break;
+		}
+	}
+}
@@ -0,0 +1,31 @@
+main() {
+    int<0,4> level = 1;
+    mut int<0,3> presses = 0;
+
+    input {
+        W => {
+            mut int<0,3> steps = 2;
+            steps++;
+            presses++;
+            say("up " + steps);
+        }
+        S => {
+            mut int<0,3> steps = 1;
+            steps--;
+            say("down " + steps);
+        }
+        A => {
+            int<0,6> level = level + 2;
+            say("shadowed " + level);
+        }
+    }
+
+    {
+        int<0,4> level = 4;
+        presses++;
+        say("inner " + level);
+    }
+
+    say("level " + level);
+    say("presses " + presses);
+}
@@ -0,0 +1,12 @@
+// A loop that never waits for input fails in both backends
+main() {
+	mut int<0, 1> stuck = 0;
+	input {
+		W => stuck++;
+		S => say("fine");
+	}
+	while (stuck == 1) {
+		say("spin");
+	}
+	say("done");
+}
@@ -0,0 +1,8 @@
+main() {
+	say("Colons: and a back\\slash");
+	say("A path a/b and " + "/c");
+	input {
+		W => say("first line\nsecond line");
+		S => say_student("alias w quit");
+	}
+}
@@ -0,0 +1,14 @@
+// Counters wrap around past either bound
+main() {
+	mut int<0, 2> c = 0;
+	mut int<-1, 1> d = 1;
+	loop {
+		input {
+			W => c++;
+			S => c--;
+			A => d++;
+			D => break;
+		}
+		say("c " + c + " d " + d);
+	}
+}